@@ -3,6 +3,9 @@
 # Prints JSON containing arguments to `app-init` function.
 
 SKIP_CLEANUP=${SKIP_CLEANUP:-false}
+SECRETS_DEADLINE_SECS=${SECRETS_DEADLINE_SECS:-null}
+LITESTREAM_SHA256=${LITESTREAM_SHA256:+\"$LITESTREAM_SHA256\"}
+LITESTREAM_SHA256=${LITESTREAM_SHA256:-null}
 
 cat <<EOF
 [
@@ -13,26 +16,49 @@ cat <<EOF
         {
             "name": "stargazers_activity_llm_chatgpt",
             "location-oci": "docker.io/getobelisk/demo_stargazers_activity_llm_openai:2025-09-28@sha256:4b10a66c80bec625a6b0a2e8a4b5192f8a2356eca19c0a6705335771a8b8b1e8",
-            "env-vars":["OPENAI_API_KEY"],
-            "lock-expiry-seconds": 10
+            "env-vars": [{"secret": "OPENAI_API_KEY"}],
+            "lock-expiry-seconds": 10,
+            "max-retries": null,
+            "retry-exp-backoff-ms": null,
+            "retry-on-err": null,
+            "max-inflight-instances": null,
+            "forward-stdout": null,
+            "forward-stderr": null
         },
         {
             "name": "stargazers_activity_github_impl",
             "location-oci": "docker.io/getobelisk/demo_stargazers_activity_github_impl:2025-09-28@sha256:8f6fc9b1379b359e085998fa2fd7c966c450327d09770807dfba4b2f75731d72",
-            "env-vars": ["GITHUB_TOKEN"],
-            "lock-expiry-seconds": 5
+            "env-vars": [{"secret": "GITHUB_TOKEN"}],
+            "lock-expiry-seconds": 5,
+            "max-retries": null,
+            "retry-exp-backoff-ms": null,
+            "retry-on-err": null,
+            "max-inflight-instances": null,
+            "forward-stdout": null,
+            "forward-stderr": null
         },
         {
             "name": "stargazers_activity_db_turso",
             "location-oci": "docker.io/getobelisk/demo_stargazers_activity_db_turso:2025-09-28@sha256:26b08b3d0c6e430944d8187a00bd9817a83ab89e11ba72d15e7533a758addf33",
-            "env-vars": ["TURSO_TOKEN", "TURSO_LOCATION"],
-            "lock-expiry-seconds": 5
+            "env-vars": [{"secret": "TURSO_TOKEN"}, {"secret": "TURSO_LOCATION"}],
+            "lock-expiry-seconds": 5,
+            "max-retries": null,
+            "retry-exp-backoff-ms": null,
+            "retry-on-err": null,
+            "max-inflight-instances": null,
+            "forward-stdout": null,
+            "forward-stderr": null
         }
     ],
     "workflow-list":[
         {
             "name": "stargazers_workflow",
-            "location-oci": "docker.io/getobelisk/demo_stargazers_workflow:2025-09-28@sha256:678d85e3e2f89d22794fd1ffc0217bf23510e1349ee150a54d5c82cc2ef75834"
+            "location-oci": "docker.io/getobelisk/demo_stargazers_workflow:2025-09-28@sha256:678d85e3e2f89d22794fd1ffc0217bf23510e1349ee150a54d5c82cc2ef75834",
+            "lock-expiry-seconds": null,
+            "retry-exp-backoff-ms": null,
+            "retry-on-trap": null,
+            "blocking-strategy": null,
+            "max-inflight-instances": null
         }
     ],
     "webhook-endpoint-list":[
@@ -40,11 +66,31 @@ cat <<EOF
             "name": "stargazers_webhook",
             "location-oci": "docker.io/getobelisk/demo_stargazers_webhook:2025-09-28@sha256:aa4dfa18d1ad7c1623163eeabb41a415ebad5296fca8f3b957987afcdb2a0f40",
             "routes": [{ "methods": ["POST", "GET"], "path": "" }],
-            "env-vars": ["GITHUB_WEBHOOK_SECRET"]
+            "env-vars": [{"secret": "GITHUB_WEBHOOK_SECRET"}],
+            "forward-stdout": null,
+            "forward-stderr": null,
+            "http-server": null
         }
-    ]
+    ],
+    "http-server-list": null,
+    "api-exposure": null,
+    "activity-stub-list": null,
+    "cron-list": null,
+    "server-tuning": null,
+    "toml-overlay": null,
+    "extra-files": null
+},
+{
+    "region": "${FLY_REGION:-ams}",
+    "obelisk-image": null,
+    "minio-image": null,
+    "obelisk-vm": null,
+    "minio-vm": null,
+    "volume-size-gb": null,
+    "litestream-sha256": $LITESTREAM_SHA256
 },
 60,
-$SKIP_CLEANUP
+$SKIP_CLEANUP,
+$SECRETS_DEADLINE_SECS
 ]
 EOF
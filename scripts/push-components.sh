@@ -25,5 +25,6 @@ just build
 
 push "target/wasm32-unknown-unknown/release_workflow/obelisk_deployer_flyio.wasm"
 push "target/wasm32-wasip2/release_webhook/webhook_healthcheck.wasm"
+push "target/wasm32-wasip2/release_webhook/webhook_api_proxy.wasm"
 
 echo "All components pushed and TOML file updated successfully."
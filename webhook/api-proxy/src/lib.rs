@@ -0,0 +1,215 @@
+use anyhow::Context as _;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use wstd::http::body::IncomingBody;
+use wstd::http::server::{Finished, Responder};
+use wstd::http::{Client, HeaderMap, Request, Response, StatusCode};
+use wstd::io::empty;
+
+/// Base URL of the WebUI, e.g. `http://[::1]:8080`.
+const UPSTREAM_ENV: &str = "API_PROXY_UPSTREAM";
+/// Bearer token expected in the `Authorization` header.
+const TOKEN_ENV: &str = "OBELISK_API_TOKEN";
+/// `user:password` expected as basic authentication.
+const BASIC_AUTH_ENV: &str = "OBELISK_API_BASIC_AUTH";
+const AUTHORIZATION: &str = "authorization";
+/// Headers describing a single connection, they must not be forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "host",
+    "http2-settings",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+enum Auth {
+    Token(String),
+    Basic(String),
+}
+
+impl Auth {
+    /// Configured credentials. Exactly one of the secrets must be set, the deployer forwards
+    /// only the one selected by `auth`.
+    fn from_env() -> Result<Auth, String> {
+        Auth::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Auth, String> {
+        let non_empty = |key| lookup(key).filter(|value| !value.is_empty());
+        match (non_empty(TOKEN_ENV), non_empty(BASIC_AUTH_ENV)) {
+            (Some(token), None) => Ok(Auth::Token(token)),
+            (None, Some(credentials)) => Ok(Auth::Basic(credentials)),
+            (Some(_), Some(_)) => Err(format!(
+                "both {TOKEN_ENV} and {BASIC_AUTH_ENV} are set, the authentication mode is ambiguous"
+            )),
+            (None, None) => Err(format!("neither {TOKEN_ENV} nor {BASIC_AUTH_ENV} is set")),
+        }
+    }
+
+    fn expected_authorization(&self) -> String {
+        match self {
+            Auth::Token(token) => format!("Bearer {token}"),
+            Auth::Basic(credentials) => format!("Basic {}", STANDARD.encode(credentials)),
+        }
+    }
+}
+
+/// Compare without short-circuiting, so that the response time does not reveal the secret.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_authorized(headers: &HeaderMap, auth: &Auth) -> bool {
+    headers.get(AUTHORIZATION).is_some_and(|actual| {
+        constant_time_eq(actual.as_bytes(), auth.expected_authorization().as_bytes())
+    })
+}
+
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    for header in HOP_BY_HOP_HEADERS {
+        headers.remove(*header);
+    }
+}
+
+async fn forward(request: Request<IncomingBody>) -> Result<Response<IncomingBody>, anyhow::Error> {
+    let upstream =
+        std::env::var(UPSTREAM_ENV).with_context(|| format!("{UPSTREAM_ENV} not set"))?;
+    let (mut parts, body) = request.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    parts.uri = format!("{}{path_and_query}", upstream.trim_end_matches('/'))
+        .parse()
+        .context("invalid upstream URI")?;
+    remove_hop_by_hop_headers(&mut parts.headers);
+    // Credentials of the proxy are not meant for the WebUI.
+    parts.headers.remove(AUTHORIZATION);
+    let mut response = Client::new()
+        .send(Request::from_parts(parts, body))
+        .await
+        .context("cannot reach the upstream")?;
+    remove_hop_by_hop_headers(response.headers_mut());
+    Ok(response)
+}
+
+async fn respond_with_status(
+    responder: Responder,
+    status: StatusCode,
+    headers: &[(&str, &str)],
+) -> Finished {
+    let mut response = Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(*name, *value);
+    }
+    responder.respond(response.body(empty()).unwrap()).await
+}
+
+#[wstd::http_server]
+async fn main(request: Request<IncomingBody>, responder: Responder) -> Finished {
+    let auth = match Auth::from_env() {
+        Ok(auth) => auth,
+        Err(err) => {
+            eprintln!("{err}, rejecting all requests");
+            return respond_with_status(responder, StatusCode::SERVICE_UNAVAILABLE, &[]).await;
+        }
+    };
+    if !is_authorized(request.headers(), &auth) {
+        // Let browsers prompt for the credentials.
+        let challenge: &[_] = match auth {
+            Auth::Token(_) => &[],
+            Auth::Basic(_) => &[("www-authenticate", r#"Basic realm="Obelisk""#)],
+        };
+        return respond_with_status(responder, StatusCode::UNAUTHORIZED, challenge).await;
+    }
+    match forward(request).await {
+        Ok(response) => responder.respond(response).await,
+        Err(err) => {
+            eprintln!("{err:?}");
+            respond_with_status(responder, StatusCode::BAD_GATEWAY, &[]).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AUTHORIZATION, Auth, BASIC_AUTH_ENV, TOKEN_ENV, constant_time_eq, is_authorized,
+        remove_hop_by_hop_headers,
+    };
+    use wstd::http::{HeaderMap, HeaderValue};
+
+    fn auth(env: &[(&str, &str)]) -> Result<Auth, String> {
+        Auth::from_lookup(|key| {
+            env.iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| (*value).to_string())
+        })
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(authorization).unwrap());
+        headers
+    }
+
+    #[test]
+    fn constant_time_eq_should_compare_contents_and_lengths() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn auth_should_require_exactly_one_secret() {
+        assert!(matches!(auth(&[(TOKEN_ENV, "t")]), Ok(Auth::Token(token)) if token == "t"));
+        assert!(matches!(
+            auth(&[(TOKEN_ENV, ""), (BASIC_AUTH_ENV, "user:password")]),
+            Ok(Auth::Basic(credentials)) if credentials == "user:password"
+        ));
+        assert!(auth(&[(TOKEN_ENV, "t"), (BASIC_AUTH_ENV, "user:password")]).is_err());
+        assert!(auth(&[]).is_err());
+    }
+
+    #[test]
+    fn bearer_token_should_be_checked() {
+        let auth = Auth::Token("token".to_string());
+        assert!(is_authorized(&headers("Bearer token"), &auth));
+        assert!(!is_authorized(&headers("Bearer other"), &auth));
+        assert!(!is_authorized(&headers("token"), &auth));
+        assert!(!is_authorized(&HeaderMap::new(), &auth));
+    }
+
+    #[test]
+    fn basic_credentials_should_be_checked() {
+        let auth = Auth::Basic("user:password".to_string());
+        // base64 of `user:password`
+        assert!(is_authorized(&headers("Basic dXNlcjpwYXNzd29yZA=="), &auth));
+        assert!(!is_authorized(&headers("Basic dXNlcjpvdGhlcg=="), &auth));
+        assert!(!is_authorized(&headers("Bearer user:password"), &auth));
+    }
+
+    #[test]
+    fn hop_by_hop_headers_should_be_removed() {
+        let mut headers = HeaderMap::new();
+        for name in [
+            "connection",
+            "keep-alive",
+            "transfer-encoding",
+            "upgrade",
+            "x-request-id",
+        ] {
+            headers.insert(name, HeaderValue::from_static("value"));
+        }
+        remove_hop_by_hop_headers(&mut headers);
+        assert_eq!(
+            vec!["x-request-id"],
+            headers.keys().map(|name| name.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::generated::{
+    obelisk::{
+        types::time::{Datetime, ScheduleAt},
+        workflow::workflow_support::{self, ClosingStrategy},
+    },
+    obelisk_flyio::activity_fly_http::{
+        self,
+        apps::App,
+        ips::{IpDetail, IpRequest},
+        machines::{ExecResponse, Machine, MachineConfig},
+        regions::Region,
+        secrets::Secret,
+        volumes::{Volume, VolumeCreateRequest},
+    },
+    testing::http::http_get,
+};
+
+/// Longest join set name passed to the runtime.
+pub(crate) const MAX_JOIN_SET_NAME_LEN: usize = 64;
+
+/// Replace characters other than ASCII alphanumerics, `-` and `_`, and truncate the name,
+/// so that names built from user input are accepted by the runtime.
+pub(crate) fn join_set_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_JOIN_SET_NAME_LEN)
+        .collect()
+}
+
+/// Activities and host functions called by the workflow.
+///
+/// Abstracted so that the workflow logic can run natively against an in-memory fake.
+pub(crate) trait Activities {
+    fn apps_get(&self, app_name: &str) -> Result<Option<App>, String>;
+    fn apps_put(&self, org_slug: &str, app_name: &str) -> Result<App, String>;
+    fn apps_delete(&self, app_name: &str, force: bool) -> Result<(), String>;
+
+    fn ips_allocate_unsafe(&self, app_name: &str, request: IpRequest) -> Result<String, String>;
+    fn ips_list(&self, app_name: &str) -> Result<Vec<IpDetail>, String>;
+    fn ips_release(&self, app_name: &str, ip: &str) -> Result<(), String>;
+
+    fn machines_list(&self, app_name: &str) -> Result<Vec<Machine>, String>;
+    fn machines_get(&self, app_name: &str, machine_id: &str) -> Result<Option<Machine>, String>;
+    fn machines_create(
+        &self,
+        app_name: &str,
+        machine_name: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<String, String>;
+    /// Replace the configuration of the machine, restarting it.
+    fn machines_update(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<(), String>;
+    fn machines_stop(&self, app_name: &str, machine_id: &str) -> Result<(), String>;
+    fn machines_restart(&self, app_name: &str, machine_id: &str) -> Result<(), String>;
+    fn machines_delete(&self, app_name: &str, machine_id: &str, force: bool) -> Result<(), String>;
+    fn machines_exec(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+    ) -> Result<ExecResponse, String>;
+
+    fn secrets_list(&self, app_name: &str) -> Result<Vec<Secret>, String>;
+    fn secrets_delete(&self, app_name: &str, secret_name: &str) -> Result<(), String>;
+
+    fn volumes_list(&self, app_name: &str) -> Result<Vec<Volume>, String>;
+    fn volumes_create(
+        &self,
+        app_name: &str,
+        request: &VolumeCreateRequest,
+    ) -> Result<Volume, String>;
+    fn volumes_delete(&self, app_name: &str, volume_id: &str) -> Result<(), String>;
+
+    fn http_get_resp(&self, url: &str) -> Result<http_get::Response, String>;
+
+    /// Persistent sleep, returns the time of waking up.
+    fn sleep(&self, schedule_at: ScheduleAt) -> Datetime;
+
+    /// Persistent sleep in a join set named after [`join_set_name`], making the reason of waiting
+    /// visible in the execution log. Returns the time of waking up.
+    fn sleep_named(&self, name: &str, schedule_at: ScheduleAt) -> Datetime;
+
+    /// Alphanumeric string with a length in the range [min_length, max_length_exclusive).
+    fn random_string(&self, min_length: u16, max_length_exclusive: u16) -> String;
+}
+
+/// Activities imported by the component.
+pub(crate) struct Imported;
+
+impl Activities for Imported {
+    fn apps_get(&self, app_name: &str) -> Result<Option<App>, String> {
+        activity_fly_http::apps::get(app_name)
+    }
+
+    fn apps_put(&self, org_slug: &str, app_name: &str) -> Result<App, String> {
+        activity_fly_http::apps::put(org_slug, app_name)
+    }
+
+    fn apps_delete(&self, app_name: &str, force: bool) -> Result<(), String> {
+        activity_fly_http::apps::delete(app_name, force)
+    }
+
+    fn ips_allocate_unsafe(&self, app_name: &str, request: IpRequest) -> Result<String, String> {
+        activity_fly_http::ips::allocate_unsafe(app_name, request)
+    }
+
+    fn ips_list(&self, app_name: &str) -> Result<Vec<IpDetail>, String> {
+        activity_fly_http::ips::list(app_name)
+    }
+
+    fn ips_release(&self, app_name: &str, ip: &str) -> Result<(), String> {
+        activity_fly_http::ips::release(app_name, ip)
+    }
+
+    fn machines_list(&self, app_name: &str) -> Result<Vec<Machine>, String> {
+        activity_fly_http::machines::list(app_name)
+    }
+
+    fn machines_get(&self, app_name: &str, machine_id: &str) -> Result<Option<Machine>, String> {
+        activity_fly_http::machines::get(app_name, machine_id)
+    }
+
+    fn machines_create(
+        &self,
+        app_name: &str,
+        machine_name: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<String, String> {
+        activity_fly_http::machines::create(app_name, machine_name, machine_config, region)
+    }
+
+    fn machines_update(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<(), String> {
+        activity_fly_http::machines::update(app_name, machine_id, machine_config, region)
+    }
+
+    fn machines_stop(&self, app_name: &str, machine_id: &str) -> Result<(), String> {
+        activity_fly_http::machines::stop(app_name, machine_id)
+    }
+
+    fn machines_restart(&self, app_name: &str, machine_id: &str) -> Result<(), String> {
+        activity_fly_http::machines::restart(app_name, machine_id)
+    }
+
+    fn machines_delete(&self, app_name: &str, machine_id: &str, force: bool) -> Result<(), String> {
+        activity_fly_http::machines::delete(app_name, machine_id, force)
+    }
+
+    fn machines_exec(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+    ) -> Result<ExecResponse, String> {
+        activity_fly_http::machines::exec(app_name, machine_id, command)
+    }
+
+    fn secrets_list(&self, app_name: &str) -> Result<Vec<Secret>, String> {
+        activity_fly_http::secrets::list(app_name)
+    }
+
+    fn secrets_delete(&self, app_name: &str, secret_name: &str) -> Result<(), String> {
+        activity_fly_http::secrets::delete(app_name, secret_name)
+    }
+
+    fn volumes_list(&self, app_name: &str) -> Result<Vec<Volume>, String> {
+        activity_fly_http::volumes::list(app_name)
+    }
+
+    fn volumes_create(
+        &self,
+        app_name: &str,
+        request: &VolumeCreateRequest,
+    ) -> Result<Volume, String> {
+        activity_fly_http::volumes::create(app_name, request)
+    }
+
+    fn volumes_delete(&self, app_name: &str, volume_id: &str) -> Result<(), String> {
+        activity_fly_http::volumes::delete(app_name, volume_id)
+    }
+
+    fn http_get_resp(&self, url: &str) -> Result<http_get::Response, String> {
+        http_get::get_resp(url)
+    }
+
+    fn sleep(&self, schedule_at: ScheduleAt) -> Datetime {
+        workflow_support::sleep(schedule_at)
+    }
+
+    fn sleep_named(&self, name: &str, schedule_at: ScheduleAt) -> Datetime {
+        // A sanitized name can still conflict with an earlier join set of the execution,
+        // sleep in a join set with a generated name then.
+        let join_set =
+            workflow_support::new_join_set_named(&join_set_name(name), ClosingStrategy::Complete)
+                .unwrap_or_else(|_| {
+                    workflow_support::new_join_set_generated(ClosingStrategy::Complete)
+                });
+        workflow_support::submit_delay(&join_set, schedule_at);
+        let _ = workflow_support::join_next(&join_set);
+        workflow_support::close(join_set);
+        workflow_support::sleep(ScheduleAt::Now)
+    }
+
+    fn random_string(&self, min_length: u16, max_length_exclusive: u16) -> String {
+        workflow_support::random_string(min_length, max_length_exclusive)
+    }
+}
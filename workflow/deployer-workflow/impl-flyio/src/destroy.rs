@@ -0,0 +1,346 @@
+use crate::activities::Activities;
+use crate::generated::{
+    obelisk::types::time::{Duration as SchedulingDuration, ScheduleAt},
+    obelisk_flyio::{
+        activity_fly_http::machines::MachineState,
+        workflow::types::{AppDestroyError, AppDestroyOptions, ResourceKind, ResourceResult},
+    },
+};
+use crate::{
+    MACHINE_START_DEADLINE, MINIO_VM_NAME, MINIO_VOLUME_NAME, SLEEP_BETWEEN_RETRIES, VM_NAME_FINAL,
+};
+
+/// A volume cannot be deleted until the machine it was attached to is destroyed.
+const MAX_VOLUME_DELETE_ATTEMPTS: u32 = 5;
+
+fn resource_result(kind: ResourceKind, id: String, res: Result<(), String>) -> ResourceResult {
+    ResourceResult {
+        kind,
+        id,
+        error: res.err(),
+    }
+}
+
+/// Stop the final VM and wait until it is stopped. The running Litestream replicator
+/// shuts Obelisk down first and then syncs the remaining changes to MinIO.
+fn final_snapshot(act: &impl Activities, app_name: &str, machine_id: &str) -> Result<(), String> {
+    act.machines_stop(app_name, machine_id)?;
+    let start_secs = act.sleep(ScheduleAt::Now).seconds;
+    loop {
+        let state = act
+            .machines_get(app_name, machine_id)?
+            .ok_or_else(|| "the final VM disappeared while stopping".to_string())?
+            .state;
+        match state {
+            MachineState::Stopped => return Ok(()),
+            MachineState::Destroying | MachineState::Destroyed | MachineState::Replaced => {
+                return Err(format!("the final VM is {state:?} instead of stopped"));
+            }
+            _ => {}
+        }
+        let current_secs = act
+            .sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+                SLEEP_BETWEEN_RETRIES.as_secs(),
+            )))
+            .seconds;
+        if current_secs - start_secs > MACHINE_START_DEADLINE.as_secs() {
+            return Err(format!(
+                "the final VM did not stop within {}s, last state is {state:?}",
+                MACHINE_START_DEADLINE.as_secs()
+            ));
+        }
+    }
+}
+
+pub(crate) fn delete_volume(
+    act: &impl Activities,
+    app_name: &str,
+    volume_id: &str,
+) -> Result<(), String> {
+    let mut attempt = 1;
+    loop {
+        match act.volumes_delete(app_name, volume_id) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= MAX_VOLUME_DELETE_ATTEMPTS => return Err(err),
+            Err(_) => {
+                attempt += 1;
+                act.sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+                    SLEEP_BETWEEN_RETRIES.as_secs(),
+                )));
+            }
+        }
+    }
+}
+
+pub(crate) fn app_destroy(
+    act: &impl Activities,
+    app_name: &str,
+    options: &AppDestroyOptions,
+) -> Result<Vec<ResourceResult>, AppDestroyError> {
+    if act
+        .apps_get(app_name)
+        .map_err(AppDestroyError::AppGetError)?
+        .is_none()
+    {
+        return Err(AppDestroyError::AppNotFound);
+    }
+    let mut results = Vec::new();
+
+    let mut machines = match act.machines_list(app_name) {
+        Ok(machines) => machines,
+        Err(err) => {
+            results.push(resource_result(
+                ResourceKind::Machine,
+                String::new(),
+                Err(err),
+            ));
+            Vec::new()
+        }
+    };
+    // Delete the final VM first, so that nothing is written to the database, MinIO VM last.
+    machines.sort_by_key(|machine| match machine.name.as_str() {
+        VM_NAME_FINAL => 0,
+        MINIO_VM_NAME => 2,
+        _ => 1,
+    });
+    if options.final_snapshot {
+        let res = match machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+        {
+            Some(machine) => final_snapshot(act, app_name, &machine.id),
+            None => Err("cannot find the final VM".to_string()),
+        };
+        let failed = res.is_err();
+        results.push(resource_result(
+            ResourceKind::Snapshot,
+            app_name.to_string(),
+            res,
+        ));
+        if failed {
+            return Err(AppDestroyError::Incomplete(results));
+        }
+    }
+    for machine in machines {
+        if options.keep_backup && machine.name == MINIO_VM_NAME {
+            continue;
+        }
+        // Ignore failure to shut down, the VM will be deleted with force.
+        let _ = act.machines_stop(app_name, &machine.id);
+        let res = act.machines_delete(app_name, &machine.id, true);
+        results.push(resource_result(ResourceKind::Machine, machine.id, res));
+    }
+
+    match act.volumes_list(app_name) {
+        Ok(volumes) => {
+            for volume in volumes {
+                if options.keep_backup && volume.name == MINIO_VOLUME_NAME {
+                    continue;
+                }
+                let res = delete_volume(act, app_name, &volume.id);
+                results.push(resource_result(ResourceKind::Volume, volume.id, res));
+            }
+        }
+        Err(err) => results.push(resource_result(
+            ResourceKind::Volume,
+            String::new(),
+            Err(err),
+        )),
+    }
+
+    // The backup is restored into the same app, keep its addresses and secrets as well.
+    if options.keep_backup {
+        return finish(results);
+    }
+
+    match act.ips_list(app_name) {
+        Ok(ips) => {
+            for ip_detail in ips {
+                let res = act.ips_release(app_name, &ip_detail.ip);
+                results.push(resource_result(ResourceKind::Ip, ip_detail.ip, res));
+            }
+        }
+        Err(err) => results.push(resource_result(ResourceKind::Ip, String::new(), Err(err))),
+    }
+
+    match act.secrets_list(app_name) {
+        Ok(secrets) => {
+            for secret in secrets {
+                let res = act.secrets_delete(app_name, &secret.name);
+                results.push(resource_result(ResourceKind::Secret, secret.name, res));
+            }
+        }
+        Err(err) => results.push(resource_result(
+            ResourceKind::Secret,
+            String::new(),
+            Err(err),
+        )),
+    }
+
+    let res = act.apps_delete(app_name, true);
+    results.push(resource_result(
+        ResourceKind::App,
+        app_name.to_string(),
+        res,
+    ));
+    finish(results)
+}
+
+fn finish(results: Vec<ResourceResult>) -> Result<Vec<ResourceResult>, AppDestroyError> {
+    if results.iter().any(|result| result.error.is_some()) {
+        Err(AppDestroyError::Incomplete(results))
+    } else {
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::app_destroy;
+    use crate::fake::{APP, FakeFly};
+    use crate::generated::obelisk_flyio::{
+        activity_fly_http::{
+            ips::{IpRequest, IpVariant, Ipv6Config},
+            machines::MachineConfig,
+            regions::Region,
+            volumes::VolumeCreateRequest,
+        },
+        workflow::types::{AppDestroyError, AppDestroyOptions, ResourceKind},
+    };
+    use crate::{
+        MINIO_VM_NAME, MINIO_VOLUME_NAME, VM_NAME_FINAL, VM_NAME_TEMP, activities::Activities as _,
+    };
+
+    fn machine_config() -> MachineConfig {
+        MachineConfig {
+            image: "image".to_string(),
+            guest: None,
+            auto_destroy: None,
+            init: None,
+            env: None,
+            restart: None,
+            stop_config: None,
+            mounts: None,
+            services: None,
+        }
+    }
+
+    fn fly_with_machines() -> FakeFly {
+        let fly = FakeFly::with_app(APP);
+        for name in [MINIO_VM_NAME, VM_NAME_TEMP, VM_NAME_FINAL] {
+            fly.machines_create(APP, name, &machine_config(), None)
+                .unwrap();
+        }
+        for name in ["db", MINIO_VOLUME_NAME] {
+            fly.volumes_create(
+                APP,
+                &VolumeCreateRequest {
+                    name: name.to_string(),
+                    size_gb: 1,
+                    region: Region::Ams,
+                    require_unique_zone: None,
+                },
+            )
+            .unwrap();
+        }
+        fly
+    }
+
+    #[test]
+    fn app_destroy_should_delete_final_vm_first_and_minio_last() {
+        let fly = fly_with_machines();
+        let options = AppDestroyOptions {
+            final_snapshot: false,
+            keep_backup: false,
+        };
+        let results = app_destroy(&fly, APP, &options).unwrap();
+        assert_eq!(
+            vec!["machine-3", "machine-2", "machine-1"],
+            results
+                .iter()
+                .filter(|result| result.kind == ResourceKind::Machine)
+                .map(|result| result.id.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["apps::delete test-app"], fly.calls("apps::delete"));
+        assert!(fly.state().apps.is_empty());
+    }
+
+    #[test]
+    fn final_snapshot_should_stop_final_vm_before_deleting_anything() {
+        let fly = fly_with_machines();
+        let options = AppDestroyOptions {
+            final_snapshot: true,
+            keep_backup: false,
+        };
+        let results = app_destroy(&fly, APP, &options).unwrap();
+        assert_eq!(ResourceKind::Snapshot, results[0].kind);
+        let calls = fly.state().calls.clone();
+        let position = |call: &str| calls.iter().position(|c| c == call).unwrap();
+        // The final VM was stopped and observed stopped before the first deletion.
+        assert!(position("machines::stop machine-3") < position("machines::get machine-3"));
+        assert!(position("machines::get machine-3") < position("machines::delete machine-3"));
+        assert!(
+            !calls[..position("machines::get machine-3")]
+                .iter()
+                .any(|call| call.starts_with("machines::delete"))
+        );
+    }
+
+    #[test]
+    fn app_destroy_should_not_delete_anything_when_snapshot_fails() {
+        let options = AppDestroyOptions {
+            final_snapshot: true,
+            keep_backup: false,
+        };
+        for activity in ["machines::stop", "machines::get"] {
+            let fly = fly_with_machines();
+            fly.fail(activity, "500 Internal Server Error");
+            let err = app_destroy(&fly, APP, &options).unwrap_err();
+            assert!(matches!(err, AppDestroyError::Incomplete(_)));
+            assert!(fly.calls("machines::delete").is_empty());
+            assert_eq!(3, fly.app(APP).machines.len());
+        }
+    }
+
+    #[test]
+    fn keep_backup_should_keep_minio_volume_ips_and_secrets() {
+        let fly = fly_with_machines();
+        fly.ips_allocate_unsafe(
+            APP,
+            IpRequest {
+                config: IpVariant::Ipv6(Ipv6Config { region: None }),
+            },
+        )
+        .unwrap();
+        fly.state_mut()
+            .apps
+            .get_mut(APP)
+            .unwrap()
+            .secrets
+            .insert("API_TOKEN".to_string(), "digest".to_string());
+        // The MinIO volume is kept even when it is not attached to the MinIO VM.
+        let options = AppDestroyOptions {
+            final_snapshot: false,
+            keep_backup: true,
+        };
+        app_destroy(&fly, APP, &options).unwrap();
+        let app = fly.app(APP);
+        assert_eq!(
+            vec![MINIO_VM_NAME],
+            app.machines
+                .iter()
+                .map(|machine| machine.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![MINIO_VOLUME_NAME],
+            app.volumes
+                .iter()
+                .map(|volume| volume.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, app.ips.len());
+        assert_eq!(1, app.secrets.len());
+    }
+}
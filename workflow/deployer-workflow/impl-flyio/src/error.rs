@@ -0,0 +1,205 @@
+use crate::generated::obelisk_flyio::{
+    activity_fly_http::machines::{ExecResponse, Machine},
+    workflow::types::{ErrorKind, ObservedMachine, StepError},
+};
+
+/// Maximum length of stdout and stderr excerpts.
+const MAX_OUTPUT_EXCERPT_LEN: usize = 1000;
+
+impl StepError {
+    /// Failed Fly.io API call. The kind and status are guessed from the activity's error message.
+    pub(crate) fn fly(step: &str, message: String) -> StepError {
+        let status = parse_status(&message);
+        StepError {
+            step: step.to_string(),
+            kind: classify(&message, status),
+            status,
+            machine_id: None,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            machine: None,
+            message,
+        }
+    }
+
+    pub(crate) fn not_found(step: &str, message: &str) -> StepError {
+        StepError {
+            step: step.to_string(),
+            kind: ErrorKind::NotFound,
+            status: None,
+            machine_id: None,
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            machine: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// Command executed on a VM did not succeed.
+    pub(crate) fn exec(
+        step: &str,
+        machine_id: &str,
+        message: String,
+        exec_response: &ExecResponse,
+    ) -> StepError {
+        StepError {
+            step: step.to_string(),
+            kind: ErrorKind::ExecFailed,
+            status: None,
+            machine_id: Some(machine_id.to_string()),
+            exit_code: exec_response.exit_code,
+            stdout: excerpt(exec_response.stdout.as_deref()),
+            stderr: excerpt(exec_response.stderr.as_deref()),
+            machine: None,
+            message,
+        }
+    }
+
+    /// The VM did not reach the `started` state.
+    pub(crate) fn not_started(
+        step: &str,
+        kind: ErrorKind,
+        message: String,
+        machine: Machine,
+    ) -> StepError {
+        StepError {
+            step: step.to_string(),
+            kind,
+            status: None,
+            machine_id: Some(machine.id.clone()),
+            exit_code: None,
+            stdout: None,
+            stderr: None,
+            machine: Some(ObservedMachine {
+                id: machine.id,
+                name: machine.name,
+                state: machine.state,
+                host_status: machine.host_status,
+                region: machine.region,
+                image: machine.config.image,
+                updated_at: machine.updated_at,
+            }),
+            message,
+        }
+    }
+
+    pub(crate) fn with_machine_id(self, machine_id: &str) -> StepError {
+        StepError {
+            machine_id: Some(machine_id.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Map an error returned by a Fly.io activity to the given variant.
+pub(crate) fn fly_error<E>(
+    variant: fn(StepError) -> E,
+    step: &'static str,
+) -> impl FnOnce(String) -> E {
+    move |message| variant(StepError::fly(step, message))
+}
+
+/// Parse the HTTP error status the activity puts in front of the response, e.g. `429 Too Many Requests`.
+/// Numbers elsewhere in the message are ignored.
+fn parse_status(message: &str) -> Option<u16> {
+    let (status, rest) = message.split_at_checked(3)?;
+    if !status.bytes().all(|byte| byte.is_ascii_digit())
+        || !(rest.is_empty() || rest.starts_with([' ', ':']))
+    {
+        return None;
+    }
+    status
+        .parse()
+        .ok()
+        .filter(|status| (400..600).contains(status))
+}
+
+fn classify(message: &str, status: Option<u16>) -> ErrorKind {
+    let message = message.to_lowercase();
+    let contains_any = |needles: &[&str]| needles.iter().any(|needle| message.contains(needle));
+    match status {
+        Some(401 | 403) => return ErrorKind::Unauthorized,
+        Some(404) => return ErrorKind::NotFound,
+        Some(408 | 504) => return ErrorKind::Timeout,
+        Some(429) => return ErrorKind::RateLimited,
+        _ => {}
+    }
+    if contains_any(&["quota", "insufficient", "capacity", "limit reached"]) {
+        ErrorKind::QuotaExceeded
+    } else if contains_any(&["pull", "manifest", "image not found"]) {
+        ErrorKind::ImagePullFailed
+    } else if contains_any(&["timeout", "timed out", "deadline"]) {
+        ErrorKind::Timeout
+    } else if contains_any(&["unauthorized", "forbidden", "permission"]) {
+        ErrorKind::Unauthorized
+    } else if contains_any(&["not found", "not_found", "does not exist"]) {
+        ErrorKind::NotFound
+    } else if contains_any(&["rate limit", "too many requests"]) {
+        ErrorKind::RateLimited
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Keep the end of the output, where the error is usually reported.
+fn excerpt(output: Option<&str>) -> Option<String> {
+    let output = output?;
+    let mut start = output.len().saturating_sub(MAX_OUTPUT_EXCERPT_LEN);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    Some(output[start..].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StepError, excerpt, parse_status};
+    use crate::generated::obelisk_flyio::workflow::types::ErrorKind;
+
+    #[test]
+    fn fly_error_should_be_classified() {
+        let err = StepError::fly(
+            "create-volume",
+            "429 Too Many Requests: rate limit exceeded".to_string(),
+        );
+        assert_eq!(Some(429), err.status);
+        assert_eq!(ErrorKind::RateLimited, err.kind);
+
+        let err = StepError::fly(
+            "start-final-vm",
+            "failed to launch VM: insufficient memory available to fulfill request".to_string(),
+        );
+        assert_eq!(None, err.status);
+        assert_eq!(ErrorKind::QuotaExceeded, err.kind);
+
+        let err = StepError::fly(
+            "start-temp-vm",
+            "failed to pull image getobelisk/obelisk:0.0.0".to_string(),
+        );
+        assert_eq!(ErrorKind::ImagePullFailed, err.kind);
+    }
+
+    #[test]
+    fn status_should_be_parsed_from_the_prefix_only() {
+        assert_eq!(Some(404), parse_status("404 Not Found"));
+        assert_eq!(Some(503), parse_status("503: upstream unavailable"));
+        assert_eq!(Some(500), parse_status("500"));
+        assert_eq!(None, parse_status("200 OK"));
+        assert_eq!(
+            None,
+            parse_status("failed to launch VM: 512 MB of memory required")
+        );
+        assert_eq!(None, parse_status("4040 requests"));
+        assert_eq!(None, parse_status("volume vol_404 not ready"));
+        assert_eq!(None, parse_status("ž4"));
+    }
+
+    #[test]
+    fn excerpt_should_keep_the_end_on_a_char_boundary() {
+        let output = format!("ž{}", "a".repeat(999));
+        assert_eq!(Some("a".repeat(999)), excerpt(Some(&output)));
+        assert_eq!(Some("short".to_string()), excerpt(Some("short")));
+    }
+}
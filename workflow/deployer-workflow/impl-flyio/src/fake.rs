@@ -0,0 +1,627 @@
+//! Stateful in-memory Fly.io used for running the workflow logic natively.
+use crate::activities::{Activities, join_set_name};
+use crate::generated::{
+    obelisk::types::time::{Datetime, Duration as SchedulingDuration, ScheduleAt},
+    obelisk_flyio::{
+        activity_fly_http::{
+            apps::App,
+            ips::{IpDetail, IpRequest},
+            machines::{ExecResponse, HostStatus, Machine, MachineConfig, MachineState},
+            regions::Region,
+            secrets::Secret,
+            volumes::{Volume, VolumeCreateRequest},
+        },
+        workflow::types::DeploymentOptions,
+    },
+    testing::http::http_get,
+};
+use crate::transfer::{ChunkWrite, sha256_hex};
+use crate::{
+    BACKUP_FILE_SCRIPT, FinalVmSettings, RESTORE_FILE_SCRIPT, minio_configure, minio_start,
+    prepare, start_final_vm,
+};
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+#[derive(Default)]
+pub(crate) struct FakeFly {
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+pub(crate) struct State {
+    /// Seconds since the start of the test, advanced by `sleep`.
+    pub(crate) now: u64,
+    pub(crate) apps: BTreeMap<String, FakeApp>,
+    /// Files written to volumes, keyed by volume ID and path.
+    pub(crate) files: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+    /// Log of all activity calls, e.g. `machines::delete machine-3`.
+    pub(crate) calls: Vec<String>,
+    /// Scripted errors of the next calls of an activity, e.g. `volumes::create`.
+    failures: HashMap<&'static str, VecDeque<String>>,
+    /// Every command containing the needle exits with the given response.
+    exec_failures: Vec<(String, ExecResponse)>,
+    /// Secrets set by the user at the given time.
+    pending_secrets: Vec<(u64, String, String)>,
+    /// Time since when the health check passes.
+    healthy_since: Option<u64>,
+    /// Results of the next health checks, taking precedence over `healthy_since`.
+    scripted_health: VecDeque<bool>,
+    /// State of created machines, started on a healthy host by default.
+    created_state: Option<(MachineState, HostStatus)>,
+    next_id: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct FakeApp {
+    pub(crate) machines: Vec<Machine>,
+    pub(crate) volumes: Vec<Volume>,
+    pub(crate) ips: Vec<IpDetail>,
+    /// Secret names and their digests.
+    pub(crate) secrets: BTreeMap<String, String>,
+}
+
+impl State {
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{prefix}-{}", self.next_id)
+    }
+
+    fn app_mut(&mut self, app_name: &str) -> Result<&mut FakeApp, String> {
+        self.apps
+            .get_mut(app_name)
+            .ok_or_else(|| format!("404 Not Found: app {app_name} not found"))
+    }
+
+    fn advance(&mut self, secs: u64) {
+        self.now += secs;
+        let now = self.now;
+        let (ready, pending) = std::mem::take(&mut self.pending_secrets)
+            .into_iter()
+            .partition(|(at, _, _)| *at <= now);
+        self.pending_secrets = pending;
+        for (_, app_name, secret) in ready {
+            let digest = self.next_id("digest");
+            if let Some(app) = self.apps.get_mut(&app_name) {
+                app.secrets.insert(secret, digest);
+            }
+        }
+    }
+}
+
+/// App name used by the tests.
+pub(crate) const APP: &str = "test-app";
+
+/// Default deployment options with a placeholder Litestream digest.
+pub(crate) fn options() -> DeploymentOptions {
+    DeploymentOptions {
+        region: None,
+        obelisk_image: None,
+        minio_image: None,
+        obelisk_vm: None,
+        minio_vm: None,
+        volume_size_gb: None,
+        litestream_sha256: Some("0".repeat(64)),
+    }
+}
+
+/// App deployed as by `app-init`, with MinIO and the final VM running.
+pub(crate) fn deployed(obelisk_toml: &str) -> FakeFly {
+    let fly = FakeFly::default();
+    prepare(&fly, "org", APP, obelisk_toml, &[], &options()).unwrap();
+    let minio_vm_id = minio_start(&fly, APP, &options()).unwrap();
+    minio_configure(&fly, APP, &minio_vm_id).unwrap();
+    start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap();
+    fly
+}
+
+impl FakeFly {
+    pub(crate) fn with_app(app_name: &str) -> FakeFly {
+        let fly = FakeFly::default();
+        fly.state_mut()
+            .apps
+            .insert(app_name.to_string(), FakeApp::default());
+        fly
+    }
+
+    pub(crate) fn state(&self) -> Ref<'_, State> {
+        self.state.borrow()
+    }
+
+    pub(crate) fn state_mut(&self) -> RefMut<'_, State> {
+        self.state.borrow_mut()
+    }
+
+    pub(crate) fn app(&self, app_name: &str) -> Ref<'_, FakeApp> {
+        Ref::map(self.state(), |state| &state.apps[app_name])
+    }
+
+    /// Fail the next call of the activity.
+    pub(crate) fn fail(&self, activity: &'static str, message: &str) {
+        self.state_mut()
+            .failures
+            .entry(activity)
+            .or_default()
+            .push_back(message.to_string());
+    }
+
+    /// Make every command containing the needle exit with the given code.
+    pub(crate) fn fail_exec(&self, needle: &str, exit_code: i32, stderr: &str) {
+        self.state_mut().exec_failures.push((
+            needle.to_string(),
+            ExecResponse {
+                exit_code: Some(exit_code),
+                exit_signal: None,
+                stderr: Some(stderr.to_string()),
+                stdout: Some(String::new()),
+            },
+        ));
+    }
+
+    pub(crate) fn clear_exec_failures(&self) {
+        self.state_mut().exec_failures.clear();
+    }
+
+    /// Make the machines created from now on end up in the given state.
+    pub(crate) fn create_machines_in(&self, state: MachineState, host_status: HostStatus) {
+        self.state_mut().created_state = Some((state, host_status));
+    }
+
+    /// Set the secret or change its digest at the given time.
+    pub(crate) fn set_secret_at(&self, app_name: &str, secret: &str, at_secs: u64) {
+        self.state_mut()
+            .pending_secrets
+            .push((at_secs, app_name.to_string(), secret.to_string()));
+    }
+
+    pub(crate) fn set_healthy_since(&self, at_secs: u64) {
+        self.state_mut().healthy_since = Some(at_secs);
+    }
+
+    pub(crate) fn script_health(&self, results: &[bool]) {
+        self.state_mut().scripted_health.extend(results);
+    }
+
+    pub(crate) fn file(&self, volume_id: &str, path: &str) -> Option<String> {
+        self.state()
+            .files
+            .get(volume_id)
+            .and_then(|files| files.get(path))
+            .map(|contents| String::from_utf8(contents.clone()).unwrap())
+    }
+
+    pub(crate) fn calls(&self, activity: &str) -> Vec<String> {
+        self.state()
+            .calls
+            .iter()
+            .filter(|call| call.starts_with(activity))
+            .cloned()
+            .collect()
+    }
+
+    /// Log the call and return a scripted failure if there is one.
+    fn call(&self, activity: &'static str, detail: &str) -> Result<RefMut<'_, State>, String> {
+        let mut state = self.state_mut();
+        state.calls.push(format!("{activity} {detail}"));
+        if let Some(err) = state
+            .failures
+            .get_mut(activity)
+            .and_then(VecDeque::pop_front)
+        {
+            return Err(err);
+        }
+        Ok(state)
+    }
+}
+
+impl Activities for FakeFly {
+    fn apps_get(&self, app_name: &str) -> Result<Option<App>, String> {
+        let state = self.call("apps::get", app_name)?;
+        Ok(state.apps.get(app_name).map(|_| App {
+            name: app_name.to_string(),
+            id: app_name.to_string(),
+        }))
+    }
+
+    fn apps_put(&self, _org_slug: &str, app_name: &str) -> Result<App, String> {
+        let mut state = self.call("apps::put", app_name)?;
+        state.apps.entry(app_name.to_string()).or_default();
+        Ok(App {
+            name: app_name.to_string(),
+            id: app_name.to_string(),
+        })
+    }
+
+    fn apps_delete(&self, app_name: &str, _force: bool) -> Result<(), String> {
+        let mut state = self.call("apps::delete", app_name)?;
+        let app = state
+            .apps
+            .remove(app_name)
+            .ok_or_else(|| format!("404 Not Found: app {app_name} not found"))?;
+        for volume in app.volumes {
+            state.files.remove(&volume.id);
+        }
+        Ok(())
+    }
+
+    fn ips_allocate_unsafe(&self, app_name: &str, request: IpRequest) -> Result<String, String> {
+        let mut state = self.call("ips::allocate-unsafe", app_name)?;
+        let ip = format!("fdaa::{}", state.next_id);
+        state.next_id += 1;
+        state.app_mut(app_name)?.ips.push(IpDetail {
+            ip: ip.clone(),
+            ip_variant: request.config,
+        });
+        Ok(ip)
+    }
+
+    fn ips_list(&self, app_name: &str) -> Result<Vec<IpDetail>, String> {
+        let mut state = self.call("ips::list", app_name)?;
+        Ok(state.app_mut(app_name)?.ips.clone())
+    }
+
+    fn ips_release(&self, app_name: &str, ip: &str) -> Result<(), String> {
+        let mut state = self.call("ips::release", ip)?;
+        let app = state.app_mut(app_name)?;
+        let idx = app
+            .ips
+            .iter()
+            .position(|ip_detail| ip_detail.ip == ip)
+            .ok_or_else(|| format!("404 Not Found: ip {ip} not found"))?;
+        app.ips.remove(idx);
+        Ok(())
+    }
+
+    fn machines_list(&self, app_name: &str) -> Result<Vec<Machine>, String> {
+        let mut state = self.call("machines::list", app_name)?;
+        Ok(state.app_mut(app_name)?.machines.clone())
+    }
+
+    fn machines_get(&self, app_name: &str, machine_id: &str) -> Result<Option<Machine>, String> {
+        let mut state = self.call("machines::get", machine_id)?;
+        Ok(state
+            .app_mut(app_name)?
+            .machines
+            .iter()
+            .find(|machine| machine.id == machine_id)
+            .cloned())
+    }
+
+    fn machines_create(
+        &self,
+        app_name: &str,
+        machine_name: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<String, String> {
+        let mut state = self.call("machines::create", machine_name)?;
+        let machine_id = state.next_id("machine");
+        let (machine_state, host_status) = state
+            .created_state
+            .unwrap_or((MachineState::Started, HostStatus::Ok));
+        let app = state.app_mut(app_name)?;
+        attach_volumes(app, &machine_id, machine_config)?;
+        app.machines.push(Machine {
+            config: machine_config.clone(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            id: machine_id.clone(),
+            instance_id: machine_id.clone(),
+            name: machine_name.to_string(),
+            state: machine_state,
+            region: region.unwrap_or(Region::Ams),
+            host_status,
+        });
+        Ok(machine_id)
+    }
+
+    fn machines_update(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<(), String> {
+        let mut state = self.call("machines::update", machine_id)?;
+        let (machine_state, host_status) = state
+            .created_state
+            .unwrap_or((MachineState::Started, HostStatus::Ok));
+        let app = state.app_mut(app_name)?;
+        find_machine(app, machine_id)?;
+        for volume in &mut app.volumes {
+            if volume.attached_machine_id.as_deref() == Some(machine_id) {
+                volume.attached_machine_id = None;
+            }
+        }
+        attach_volumes(app, machine_id, machine_config)?;
+        let machine = find_machine(app, machine_id)?;
+        machine.config = machine_config.clone();
+        machine.region = region.unwrap_or(machine.region);
+        machine.state = machine_state;
+        machine.host_status = host_status;
+        Ok(())
+    }
+
+    fn machines_stop(&self, app_name: &str, machine_id: &str) -> Result<(), String> {
+        let mut state = self.call("machines::stop", machine_id)?;
+        let machine = find_machine(state.app_mut(app_name)?, machine_id)?;
+        machine.state = MachineState::Stopped;
+        Ok(())
+    }
+
+    fn machines_restart(&self, app_name: &str, machine_id: &str) -> Result<(), String> {
+        let mut state = self.call("machines::restart", machine_id)?;
+        let machine = find_machine(state.app_mut(app_name)?, machine_id)?;
+        machine.state = MachineState::Started;
+        Ok(())
+    }
+
+    fn machines_delete(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        _force: bool,
+    ) -> Result<(), String> {
+        let mut state = self.call("machines::delete", machine_id)?;
+        let app = state.app_mut(app_name)?;
+        find_machine(app, machine_id)?;
+        app.machines.retain(|machine| machine.id != machine_id);
+        for volume in &mut app.volumes {
+            if volume.attached_machine_id.as_deref() == Some(machine_id) {
+                volume.attached_machine_id = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn machines_exec(
+        &self,
+        app_name: &str,
+        machine_id: &str,
+        command: &[String],
+    ) -> Result<ExecResponse, String> {
+        let mut state = self.call("machines::exec", &command.join(" "))?;
+        let app = state.app_mut(app_name)?;
+        find_machine(app, machine_id)?;
+        let volume_id = app
+            .volumes
+            .iter()
+            .find(|volume| volume.attached_machine_id.as_deref() == Some(machine_id))
+            .map(|volume| volume.id.clone());
+        let joined = command.join(" ");
+        if let Some((_, exec_response)) = state
+            .exec_failures
+            .iter()
+            .find(|(needle, _)| joined.contains(needle.as_str()))
+        {
+            return Ok(exec_response.clone());
+        }
+        let files = state
+            .files
+            .entry(volume_id.unwrap_or_default())
+            .or_default();
+        let mut exec_response = ExecResponse {
+            exit_code: Some(0),
+            exit_signal: None,
+            stderr: Some(String::new()),
+            stdout: Some(String::new()),
+        };
+        if let Some(chunk) = ChunkWrite::parse(command) {
+            chunk.apply(files.entry(chunk.path.to_string()).or_default())?;
+            return Ok(exec_response);
+        }
+        match command {
+            [sha256sum, path] if sha256sum == "sha256sum" => match files.get(path) {
+                Some(contents) => {
+                    let digest = sha256_hex(contents);
+                    exec_response.stdout = Some(format!("{digest}  {path}\n"));
+                }
+                None => {
+                    exec_response.exit_code = Some(1);
+                    exec_response.stderr = Some(format!("sha256sum: {path}: No such file"));
+                }
+            },
+            [mv, from, to] if mv == "mv" => match files.remove(from) {
+                Some(contents) => {
+                    files.insert(to.clone(), contents);
+                }
+                None => {
+                    exec_response.exit_code = Some(1);
+                    exec_response.stderr = Some(format!("mv: can't rename '{from}'"));
+                }
+            },
+            [cp, from, to] if cp == "cp" => match files.get(from).cloned() {
+                Some(contents) => {
+                    files.insert(to.clone(), contents);
+                }
+                None => {
+                    exec_response.exit_code = Some(1);
+                    exec_response.stderr = Some(format!("cp: cannot stat '{from}'"));
+                }
+            },
+            [sh, c, script, _, path, backup_path]
+                if sh == "sh" && c == "-c" && script == BACKUP_FILE_SCRIPT =>
+            {
+                match files.get(path).cloned() {
+                    Some(contents) => files.insert(backup_path.clone(), contents),
+                    None => files.remove(backup_path),
+                };
+            }
+            [sh, c, script, _, path, backup_path]
+                if sh == "sh" && c == "-c" && script == RESTORE_FILE_SCRIPT =>
+            {
+                match files.get(backup_path).cloned() {
+                    Some(contents) => files.insert(path.clone(), contents),
+                    None => files.remove(path),
+                };
+            }
+            [rm, f, path] if rm == "rm" && f == "-f" => {
+                files.remove(path);
+            }
+            [test, e, path] if test == "test" && e == "-e" => {
+                if !files.contains_key(path) {
+                    exec_response.exit_code = Some(1);
+                }
+            }
+            [cat, path] if cat == "cat" => match files.get(path) {
+                Some(contents) => {
+                    exec_response.stdout = Some(String::from_utf8_lossy(contents).into_owned());
+                }
+                None => {
+                    exec_response.exit_code = Some(1);
+                    exec_response.stderr = Some(format!("cat: can't open '{path}'"));
+                }
+            },
+            _ => {}
+        }
+        Ok(exec_response)
+    }
+
+    fn secrets_list(&self, app_name: &str) -> Result<Vec<Secret>, String> {
+        let mut state = self.call("secrets::list", app_name)?;
+        Ok(state
+            .app_mut(app_name)?
+            .secrets
+            .iter()
+            .map(|(name, digest)| Secret {
+                name: name.clone(),
+                digest: digest.clone(),
+            })
+            .collect())
+    }
+
+    fn secrets_delete(&self, app_name: &str, secret_name: &str) -> Result<(), String> {
+        let mut state = self.call("secrets::delete", secret_name)?;
+        state.app_mut(app_name)?.secrets.remove(secret_name);
+        Ok(())
+    }
+
+    fn volumes_list(&self, app_name: &str) -> Result<Vec<Volume>, String> {
+        let mut state = self.call("volumes::list", app_name)?;
+        Ok(state.app_mut(app_name)?.volumes.clone())
+    }
+
+    fn volumes_create(
+        &self,
+        app_name: &str,
+        request: &VolumeCreateRequest,
+    ) -> Result<Volume, String> {
+        let mut state = self.call("volumes::create", &request.name)?;
+        let volume = Volume {
+            id: state.next_id("vol"),
+            name: request.name.clone(),
+            state: "created".to_string(),
+            region: request.region,
+            size_gb: request.size_gb,
+            encrypted: true,
+            attached_machine_id: None,
+            host_status: "ok".to_string(),
+            created_at: String::new(),
+            blocks: 0,
+            block_size: 0,
+            blocks_free: 0,
+            blocks_avail: 0,
+            bytes_used: 0,
+            bytes_total: 0,
+        };
+        state.app_mut(app_name)?.volumes.push(volume.clone());
+        Ok(volume)
+    }
+
+    fn volumes_delete(&self, app_name: &str, volume_id: &str) -> Result<(), String> {
+        let mut state = self.call("volumes::delete", volume_id)?;
+        let app = state.app_mut(app_name)?;
+        let idx = app
+            .volumes
+            .iter()
+            .position(|volume| volume.id == volume_id)
+            .ok_or_else(|| format!("404 Not Found: volume {volume_id} not found"))?;
+        if app.volumes[idx].attached_machine_id.is_some() {
+            return Err(format!("volume {volume_id} is attached to a machine"));
+        }
+        app.volumes.remove(idx);
+        state.files.remove(volume_id);
+        Ok(())
+    }
+
+    fn http_get_resp(&self, url: &str) -> Result<http_get::Response, String> {
+        let mut state = self.call("http::get", url)?;
+        let healthy = match state.scripted_health.pop_front() {
+            Some(healthy) => healthy,
+            None => state.healthy_since.is_some_and(|since| since <= state.now),
+        };
+        let status_code = if healthy { 200 } else { 503 };
+        Ok(http_get::Response {
+            body: Vec::new(),
+            status_code,
+        })
+    }
+
+    fn sleep(&self, schedule_at: ScheduleAt) -> Datetime {
+        let mut state = self.state_mut();
+        let secs = match schedule_at {
+            ScheduleAt::Now => 0,
+            ScheduleAt::At(datetime) => datetime.seconds.saturating_sub(state.now),
+            ScheduleAt::In(SchedulingDuration::Milliseconds(millis)) => millis.div_ceil(1000),
+            ScheduleAt::In(SchedulingDuration::Seconds(secs)) => secs,
+            ScheduleAt::In(SchedulingDuration::Minutes(mins)) => u64::from(mins) * 60,
+            ScheduleAt::In(SchedulingDuration::Hours(hours)) => u64::from(hours) * 3600,
+            ScheduleAt::In(SchedulingDuration::Days(days)) => u64::from(days) * 86400,
+        };
+        state.advance(secs);
+        Datetime {
+            seconds: state.now,
+            nanoseconds: 0,
+        }
+    }
+
+    fn sleep_named(&self, name: &str, schedule_at: ScheduleAt) -> Datetime {
+        self.state_mut()
+            .calls
+            .push(format!("sleep {}", join_set_name(name)));
+        self.sleep(schedule_at)
+    }
+
+    fn random_string(&self, min_length: u16, _max_length_exclusive: u16) -> String {
+        let id = self.state_mut().next_id("random");
+        format!("{id:0>width$}", width = usize::from(min_length))
+    }
+}
+
+/// Mount by volume ID, or by name of the only unattached volume with that name.
+fn attach_volumes(
+    app: &mut FakeApp,
+    machine_id: &str,
+    machine_config: &MachineConfig,
+) -> Result<(), String> {
+    for mount in machine_config.mounts.iter().flatten() {
+        let idx = app
+            .volumes
+            .iter()
+            .position(|volume| volume.id == mount.volume)
+            .map_or_else(
+                || {
+                    let mut candidates = app.volumes.iter().enumerate().filter(|(_, volume)| {
+                        volume.name == mount.volume && volume.attached_machine_id.is_none()
+                    });
+                    match (candidates.next(), candidates.next()) {
+                        (Some((idx, _)), None) => Ok(idx),
+                        (Some(_), Some(_)) => {
+                            Err(format!("several unattached volumes named {}", mount.volume))
+                        }
+                        (None, _) => Err(format!("no unattached volume named {}", mount.volume)),
+                    }
+                },
+                Ok,
+            )?;
+        app.volumes[idx].attached_machine_id = Some(machine_id.to_string());
+    }
+    Ok(())
+}
+
+fn find_machine<'a>(app: &'a mut FakeApp, machine_id: &str) -> Result<&'a mut Machine, String> {
+    app.machines
+        .iter_mut()
+        .find(|machine| machine.id == machine_id)
+        .ok_or_else(|| format!("404 Not Found: machine {machine_id} not found"))
+}
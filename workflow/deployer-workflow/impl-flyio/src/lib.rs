@@ -1,37 +1,65 @@
+// Error types are generated from WIT and returned by value.
+#![allow(clippy::result_large_err)]
+mod activities;
+mod destroy;
+mod error;
+#[cfg(test)]
+mod fake;
+mod options;
+mod plan;
+mod reconcile;
+mod resume;
+mod saga;
+mod sync;
 mod toml;
+mod transfer;
+mod watch;
 mod generated {
     #![allow(clippy::empty_line_after_outer_attr)]
     include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 }
+use activities::{Activities, Imported, MAX_JOIN_SET_NAME_LEN};
 use const_format::formatcp;
+use error::fly_error;
 use generated::{
     export,
     exports::obelisk_flyio::workflow::workflow::Guest,
-    obelisk::{
-        types::time::{Duration as SchedulingDuration, ScheduleAt},
-        workflow::workflow_support,
-    },
+    obelisk::types::time::{Duration as SchedulingDuration, ScheduleAt},
     obelisk_flyio::{
         activity_fly_http::{
-            self,
             ips::{IpRequest, IpVariant, Ipv6Config},
             machines::{
-                CpuKind, GuestConfig, InitConfig, MachineConfig, MachineRestart, MachineState,
-                Mount, PortConfig, PortHandler, RestartPolicy, ServiceConfig, ServiceProtocol,
+                ExecResponse, HostStatus, InitConfig, Machine, MachineConfig, MachineRestart,
+                MachineState, Mount, PortConfig, PortHandler, RestartPolicy, ServiceConfig,
+                ServiceProtocol,
             },
-            regions::Region,
             volumes::VolumeCreateRequest,
         },
         workflow::{
-            types::{AppCleanupFailed, AppInitModifyError},
-            workflow::{self as workflow_import, AppInitError, ObeliskConfig},
+            types::{
+                ApiAuth, ApiExposure, ApiProxy, AppCleanup, AppDestroyError, AppDestroyOptions,
+                AppInitModifyError, AppRollbackFailed, AppUpdateError, DeploymentPlan, DriftReport,
+                EnvVar, ErrorKind, ExtraFile, HttpExposure, HttpServer, KeptVolume, ReconcileError,
+                ResourceResult, RestoreError, RestoreRollbackFailed, SecretRotation,
+                SecretsTimeout, StepError, SyncSecretsError, SyncSecretsOptions, WatchError,
+                WatchOptions, WatchReport,
+            },
+            workflow::{self as workflow_import, AppInitError, DeploymentOptions, ObeliskConfig},
         },
     },
     testing::http::http_get,
 };
 use hashbrown::HashSet;
+use plan::plan;
+use reconcile::reconcile;
+use resume::{inspect_progress, resume_prepare};
+use saga::Saga;
+use std::collections::BTreeMap;
 use std::time::Duration;
-use toml::serialize_obelisk_toml;
+use sync::{store_secret_digests, sync_secrets};
+use toml::{serialize_litestream_yml, serialize_obelisk_toml};
+use transfer::put_file;
+use watch::watch;
 
 struct Component;
 export!(Component with_types_in generated);
@@ -42,134 +70,222 @@ const VM_NAME_TEMP: &str = "temp";
 const MAX_VM_FAILURE_RETRIES: u32 = 5;
 
 const MINIO_VM_NAME: &str = "minio";
-const MINIO_IMAGE: &str = "minio/minio:RELEASE.2025-09-07T16-13-09Z-cpuv1";
+const MINIO_VOLUME_NAME: &str = "minio_data";
+const MINIO_DATA_PATH: &str = "/data";
 const MINIO_BUCKET_NAME: &str = "litestream-bucket";
+const MINIO_API_PORT: u16 = 9000;
+const MINIO_ROOT_USER_ENV: &str = "MINIO_ROOT_USER";
+const MINIO_ROOT_PASSWORD_ENV: &str = "MINIO_ROOT_PASSWORD";
+const MINIO_CREDENTIAL_LENGTH: u16 = 32;
 
 const VM_NAME_FINAL: &str = "obelisk";
 const VOLUME_MOUNT_PATH: &str = "/volume";
-const FINAL_IMAGE: &str = "getobelisk/obelisk:0.25.3-ubuntu";
 const OBELISK_TOML_PATH: &str = formatcp!("{VOLUME_MOUNT_PATH}/obelisk.toml");
+const OBELISK_TOML_BACKUP_PATH: &str = formatcp!("{VOLUME_MOUNT_PATH}/obelisk.toml.bak");
+const EXTRA_FILES_BACKUP_DIR: &str = formatcp!("{VOLUME_MOUNT_PATH}/extra-files.bak");
+// The file and its backup are passed as positional parameters. A missing backup records that
+// the file did not exist, restoring it deletes the file.
+const BACKUP_FILE_SCRIPT: &str = r#"if [ -e "$1" ]; then cp "$1" "$2"; else rm -f "$2"; fi"#;
+const RESTORE_FILE_SCRIPT: &str = r#"if [ -e "$2" ]; then cp "$2" "$1"; else rm -f "$1"; fi"#;
+const SECRET_DIGESTS_PATH: &str = formatcp!("{VOLUME_MOUNT_PATH}/secret-digests");
 const OBELISK_BIN_PATH: &str = "/obelisk/obelisk";
-const REGION: Region = Region::Ams;
+const SQLITE_DIRECTORY: &str = formatcp!("{VOLUME_MOUNT_PATH}/obelisk-sqlite");
+const SQLITE_FILE_PATH: &str = formatcp!("{SQLITE_DIRECTORY}/obelisk.sqlite");
+const WASM_CACHE_DIRECTORY: &str = formatcp!("{VOLUME_MOUNT_PATH}/wasm");
+const CODEGEN_CACHE_DIRECTORY: &str = formatcp!("{VOLUME_MOUNT_PATH}/codegen");
+const LOG_DIRECTORY: &str = formatcp!("{VOLUME_MOUNT_PATH}/logs");
+const LITESTREAM_VERSION: &str = "v0.3.13";
+// Published SHA-256 of the tarball at `LITESTREAM_URL`, bump it together with `LITESTREAM_VERSION`.
+// Empty while no digest is pinned, `litestream-sha256` must be set meanwhile.
+const LITESTREAM_SHA256: &str = "";
+const LITESTREAM_URL: &str = formatcp!(
+    "https://github.com/benbjohnson/litestream/releases/download/{LITESTREAM_VERSION}/litestream-{LITESTREAM_VERSION}-linux-amd64.tar.gz"
+);
+const LITESTREAM_DIR: &str = formatcp!("{VOLUME_MOUNT_PATH}/litestream");
+const LITESTREAM_BIN_PATH: &str = formatcp!("{LITESTREAM_DIR}/litestream");
+const LITESTREAM_TARBALL_PATH: &str = formatcp!("{LITESTREAM_DIR}/litestream.tar.gz");
+const LITESTREAM_YML_PATH: &str = formatcp!("{VOLUME_MOUNT_PATH}/litestream.yml");
+const WEBHOOK_SERVER_NAME: &str = "webhook_server";
 const WEBHOOK_INTERNAL_PORT: u16 = 9090;
+const API_INTERNAL_PORT: u16 = 5005;
+const WEBUI_INTERNAL_PORT: u16 = 8080;
+const API_PROXY_SERVER_NAME: &str = "api_proxy_server";
+const API_PROXY_WEBHOOK_NAME: &str = "webhook_api_proxy";
+const API_PROXY_INTERNAL_PORT: u16 = 9092;
+const API_PROXY_EXTERNAL_PORT: u16 = 8443;
 const HEALTHCHECK_INTERNAL_PORT: u16 = 9091;
 const HEALTHCHECK_EXTERNAL_PORT: u16 = 444;
 const SLEEP_BETWEEN_RETRIES: Duration = Duration::from_secs(10);
+const MACHINE_START_DEADLINE: Duration = Duration::from_secs(300);
 const SLEEP_AFTER_TEMP_VM_SHUTDOWN: Duration = Duration::from_secs(5);
+const MAX_SECRET_DIGESTS_ATTEMPTS: u32 = 3;
 
-fn allocate_ip(app_name: &str) -> Result<(), AppInitModifyError> {
-    activity_fly_http::ips::allocate_unsafe(
+fn allocate_ip(act: &impl Activities, app_name: &str) -> Result<(), AppInitModifyError> {
+    act.ips_allocate_unsafe(
         app_name,
         IpRequest {
             config: IpVariant::Ipv6(Ipv6Config { region: None }),
         },
     )
     .map(|_ip| ())
-    .map_err(AppInitModifyError::IpAllocateError)?;
+    .map_err(fly_error(
+        AppInitModifyError::IpAllocateError,
+        "allocate-ip",
+    ))?;
     // Since this API is not idempotent, make sure just one IP has been allocated.
-    let ips =
-        activity_fly_http::ips::list(app_name).map_err(AppInitModifyError::IpAllocateError)?;
+    let ips = act
+        .ips_list(app_name)
+        .map_err(fly_error(AppInitModifyError::IpAllocateError, "list-ips"))?;
     for ip_detail in ips.into_iter().skip(1) {
-        activity_fly_http::ips::release(app_name, &ip_detail.ip)
-            .map_err(AppInitModifyError::IpAllocateError)?;
+        act.ips_release(app_name, &ip_detail.ip)
+            .map_err(fly_error(AppInitModifyError::IpAllocateError, "release-ip"))?;
     }
     Ok(())
 }
 
-fn wait_until_started(app_name: &str, machine_id: &str) -> Result<(), AppInitModifyError> {
-    for _ in 0..10 {
-        let machine = activity_fly_http::machines::get(app_name, machine_id)
-            .map_err(AppInitModifyError::TempVmError)?;
-        let state = machine
+/// Wait until the VM is started.
+/// Fail early if the VM reaches a state it cannot start from, or when the deadline passes.
+fn wait_until_started(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    variant: fn(StepError) -> AppInitModifyError,
+) -> Result<(), AppInitModifyError> {
+    let start_secs = act.sleep(ScheduleAt::Now).seconds;
+    loop {
+        let machine = act
+            .machines_get(app_name, machine_id)
+            .map_err(|err| {
+                variant(StepError::fly("wait-until-started", err).with_machine_id(machine_id))
+            })?
             .ok_or_else(|| {
-                AppInitModifyError::TempVmError(
-                    "cannot find temp VM that was created successfuly".to_string(),
+                variant(
+                    StepError::not_found(
+                        "wait-until-started",
+                        "cannot find the VM that was created successfully",
+                    )
+                    .with_machine_id(machine_id),
                 )
-            })?
-            .state;
-        if state == MachineState::Started {
-            break;
+            })?;
+        if machine.host_status == HostStatus::Unreachable
+            || matches!(
+                machine.state,
+                MachineState::Stopped
+                    | MachineState::Destroying
+                    | MachineState::Destroyed
+                    | MachineState::Replaced
+            )
+        {
+            let message = format!(
+                "VM is {:?}, host status is {:?}",
+                machine.state, machine.host_status
+            );
+            return Err(variant(StepError::not_started(
+                "wait-until-started",
+                ErrorKind::MachineNotStarted,
+                message,
+                machine,
+            )));
+        }
+        if machine.state == MachineState::Started {
+            return Ok(());
+        }
+        let current_secs = act
+            .sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+                SLEEP_BETWEEN_RETRIES.as_secs(),
+            )))
+            .seconds;
+        if current_secs - start_secs > MACHINE_START_DEADLINE.as_secs() {
+            let message = format!(
+                "VM did not start within {}s, last state is {:?}",
+                MACHINE_START_DEADLINE.as_secs(),
+                machine.state
+            );
+            return Err(variant(StepError::not_started(
+                "wait-until-started",
+                ErrorKind::Timeout,
+                message,
+                machine,
+            )));
         }
-        workflow_support::sleep(ScheduleAt::In(SchedulingDuration::Seconds(
-            SLEEP_BETWEEN_RETRIES.as_secs(),
-        )));
     }
-    Ok(())
 }
 
-fn setup_volume(app_name: &str, obelisk_toml: &str) -> Result<(), AppInitModifyError> {
-    // Create a volume
-    activity_fly_http::volumes::create(
-        app_name,
-        &VolumeCreateRequest {
-            name: VOLUME_NAME.to_string(),
-            size_gb: 1,
-            region: REGION,
-            require_unique_zone: None,
-        },
-    )
-    .map_err(AppInitModifyError::VolumeCreateError)?;
-
-    // Launch a temporary VM
-    let temp_vm_id = activity_fly_http::machines::create(
-        app_name,
-        VM_NAME_TEMP,
-        &MachineConfig {
-            image: FINAL_IMAGE.to_string(),
-            guest: Some(GuestConfig {
-                cpu_kind: Some(CpuKind::Shared),
-                cpus: Some(1),
-                memory_mb: Some(256),
-                kernel_args: None,
-            }),
-            auto_destroy: None, // Some(false) - was creating a stopped machine
-            init: Some(InitConfig {
-                entrypoint: Some(vec!["/usr/bin/sleep".to_string()]),
-                cmd: Some(vec!["infinity".to_string()]),
-                exec: None,
-                kernel_args: None,
-                swap_size_mb: Some(256),
-                tty: None,
-            }),
-            env: None,
-            restart: Some(MachineRestart {
-                max_retries: None,
-                policy: RestartPolicy::No,
-            }),
-            stop_config: None,
-            mounts: Some(vec![Mount {
-                volume: VOLUME_NAME.to_string(),
-                path: VOLUME_MOUNT_PATH.to_string(),
-            }]),
-            services: None,
-        },
-        Some(REGION),
-    )
-    .map_err(AppInitModifyError::TempVmError)?;
+/// Execute a command on the VM, failing unless it exits with zero.
+pub(crate) fn exec_checked(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    step: &str,
+    command: Vec<String>,
+) -> Result<ExecResponse, StepError> {
+    let exec_response = act
+        .machines_exec(app_name, machine_id, &command)
+        .map_err(|err| StepError::fly(step, err).with_machine_id(machine_id))?;
+    if exec_response.exit_code != Some(0) {
+        let message = format!(
+            "`{}` exited with {:?}",
+            command.first().map(String::as_str).unwrap_or_default(),
+            exec_response.exit_code
+        );
+        return Err(StepError::exec(step, machine_id, message, &exec_response));
+    }
+    Ok(exec_response)
+}
 
-    wait_until_started(app_name, &temp_vm_id)?;
+fn write_file(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    path: &str,
+    contents: &str,
+) -> Result<(), AppInitModifyError> {
+    put_file(act, app_name, machine_id, path, contents.as_bytes())
+        .map_err(AppInitModifyError::VolumeWriteError)
+}
 
-    // Write obelisk.toml
-    let exec_response = activity_fly_http::machines::exec(
+/// Download Litestream onto the volume, the Obelisk image does not contain it.
+/// The tarball is extracted only if it matches the pinned digest.
+fn install_litestream(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    litestream_sha256: &str,
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
         app_name,
-        &temp_vm_id,
-        &[
+        machine_id,
+        "install-litestream",
+        vec![
             "sh".to_string(),
             "-c".to_string(),
-            format!("cat <<EOF > {OBELISK_TOML_PATH}\n{obelisk_toml}"),
+            format!(
+                "command -v curl >/dev/null || {{ echo 'curl not found in the image' >&2; exit 127; }} && \
+                mkdir -p {LITESTREAM_DIR} && \
+                curl -fsSL -o {LITESTREAM_TARBALL_PATH} {LITESTREAM_URL} && \
+                echo '{litestream_sha256}  {LITESTREAM_TARBALL_PATH}' | sha256sum -c - && \
+                tar -xzf {LITESTREAM_TARBALL_PATH} -C {LITESTREAM_DIR} && \
+                rm {LITESTREAM_TARBALL_PATH}"
+            ),
         ],
     )
-    .map_err(AppInitModifyError::VolumeWriteError)?;
-    if exec_response.exit_code != Some(0) {
-        return Err(AppInitModifyError::VolumeWriteError(format!(
-            "cannot write obelisk.toml - {exec_response:?}"
-        )));
-    }
-    // Download WASM Components, verify configuration.
-    let exec_response = activity_fly_http::machines::exec(
+    .map(|_| ())
+    .map_err(AppInitModifyError::VolumeWriteError)
+}
+
+/// Download WASM Components, verify configuration.
+fn verify_obelisk_toml(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
         app_name,
-        &temp_vm_id,
-        &[
+        machine_id,
+        "verify-config",
+        vec![
             OBELISK_BIN_PATH.to_string(),
             "server".to_string(),
             "verify".to_string(),
@@ -178,335 +294,2497 @@ fn setup_volume(app_name: &str, obelisk_toml: &str) -> Result<(), AppInitModifyE
             OBELISK_TOML_PATH.to_string(),
         ],
     )
-    .map_err(AppInitModifyError::VerifyError)?;
-    if exec_response.exit_code != Some(0) {
-        return Err(AppInitModifyError::VolumeWriteError(format!(
-            "cannot verify config - {exec_response:?}"
-        )));
-    }
+    .map(|_| ())
+    .map_err(AppInitModifyError::VerifyError)
+}
+
+/// Copy a file on the volume using the given VM.
+fn copy_file(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    from: &str,
+    to: &str,
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "copy-file",
+        vec!["cp".to_string(), from.to_string(), to.to_string()],
+    )
+    .map(|_| ())
+    .map_err(AppInitModifyError::VolumeWriteError)
+}
+
+fn create_volume(
+    act: &impl Activities,
+    app_name: &str,
+    volume_name: &str,
+    options: &DeploymentOptions,
+) -> Result<String, AppInitModifyError> {
+    act.volumes_create(
+        app_name,
+        &VolumeCreateRequest {
+            name: volume_name.to_string(),
+            size_gb: options.volume_size_gb(),
+            region: options.region(),
+            require_unique_zone: None,
+        },
+    )
+    .map(|volume| volume.id)
+    .map_err(fly_error(
+        AppInitModifyError::VolumeCreateError,
+        "create-volume",
+    ))
+}
+
+/// Launch a temporary VM with the volume mounted.
+fn start_temp_vm(
+    act: &impl Activities,
+    app_name: &str,
+    volume: &str,
+    options: &DeploymentOptions,
+    env: Option<Vec<(String, String)>>,
+) -> Result<String, AppInitModifyError> {
+    let vm_options = options.obelisk_vm();
+    let temp_vm_id = act
+        .machines_create(
+            app_name,
+            VM_NAME_TEMP,
+            &MachineConfig {
+                image: options.obelisk_image().to_string(),
+                guest: Some(vm_options.guest_config()),
+                auto_destroy: None, // Some(false) - was creating a stopped machine
+                init: Some(InitConfig {
+                    entrypoint: Some(vec!["/usr/bin/sleep".to_string()]),
+                    cmd: Some(vec!["infinity".to_string()]),
+                    exec: None,
+                    kernel_args: None,
+                    swap_size_mb: vm_options.swap_size_mb,
+                    tty: None,
+                }),
+                env,
+                restart: Some(MachineRestart {
+                    max_retries: None,
+                    policy: RestartPolicy::No,
+                }),
+                stop_config: None,
+                mounts: Some(vec![Mount {
+                    volume: volume.to_string(),
+                    path: VOLUME_MOUNT_PATH.to_string(),
+                }]),
+                services: None,
+            },
+            Some(options.region()),
+        )
+        .map_err(fly_error(AppInitModifyError::TempVmError, "start-temp-vm"))?;
+
+    wait_until_started(act, app_name, &temp_vm_id, AppInitModifyError::TempVmError)?;
+    Ok(temp_vm_id)
+}
+
+fn delete_temp_vm(
+    act: &impl Activities,
+    app_name: &str,
+    temp_vm_id: &str,
+) -> Result<(), AppInitModifyError> {
     // Attempt to shutdown the temp VM.
     // Ignore failure to shut down, temp VM will be deleted with force.
-    let _ = activity_fly_http::machines::stop(app_name, &temp_vm_id);
+    let _ = act.machines_stop(app_name, temp_vm_id);
     // Wait a bit for clean shutdown
-    workflow_support::sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+    act.sleep(ScheduleAt::In(SchedulingDuration::Seconds(
         SLEEP_AFTER_TEMP_VM_SHUTDOWN.as_secs(),
     )));
     // Destroy the VM with force.
-    activity_fly_http::machines::delete(app_name, &temp_vm_id, true)
-        .map_err(AppInitModifyError::TempVmError)?;
+    act.machines_delete(app_name, temp_vm_id, true)
+        .map_err(|err| {
+            AppInitModifyError::TempVmError(
+                StepError::fly("delete-temp-vm", err).with_machine_id(temp_vm_id),
+            )
+        })
+}
 
+/// Place the extra files of the configuration, creating their directories.
+fn write_extra_files(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    extra_files: &[ExtraFile],
+) -> Result<(), AppInitModifyError> {
+    for extra_file in extra_files {
+        if let Some((directory, _)) = extra_file.path.rsplit_once('/')
+            && directory != VOLUME_MOUNT_PATH
+        {
+            exec_checked(
+                act,
+                app_name,
+                machine_id,
+                "create-directory",
+                vec!["mkdir".to_string(), "-p".to_string(), directory.to_string()],
+            )
+            .map_err(AppInitModifyError::VolumeWriteError)?;
+        }
+        write_file(
+            act,
+            app_name,
+            machine_id,
+            &extra_file.path,
+            &extra_file.contents,
+        )?;
+    }
     Ok(())
 }
 
-fn bail_on_app_deletion(app_name: &str) -> Result<(), AppInitModifyError> {
-    match activity_fly_http::apps::get(app_name) {
-        Ok(None) => Err(AppInitModifyError::AppDeleted),
-        _ => Ok(()),
+fn extra_file_backup_path(idx: usize) -> String {
+    format!("{EXTRA_FILES_BACKUP_DIR}/{idx}")
+}
+
+/// Back up the extra files about to be overwritten, indexed by their position in the list.
+fn backup_extra_files(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    extra_files: &[ExtraFile],
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "create-directory",
+        vec![
+            "mkdir".to_string(),
+            "-p".to_string(),
+            EXTRA_FILES_BACKUP_DIR.to_string(),
+        ],
+    )
+    .map_err(AppInitModifyError::VolumeWriteError)?;
+    for (idx, extra_file) in extra_files.iter().enumerate() {
+        run_file_script(
+            act,
+            app_name,
+            machine_id,
+            "backup-file",
+            BACKUP_FILE_SCRIPT,
+            &extra_file.path,
+            &extra_file_backup_path(idx),
+        )?;
     }
+    Ok(())
 }
 
-// Sleep until all requested secrets are stored in the app or the app is deleted.
-fn wait_for_secrets(
+/// Put back the extra files backed up by [`backup_extra_files`], deleting the ones that did not exist.
+fn restore_extra_files(
+    act: &impl Activities,
     app_name: &str,
-    required_secrets: HashSet<String>,
+    machine_id: &str,
+    extra_files: &[ExtraFile],
 ) -> Result<(), AppInitModifyError> {
-    while !required_secrets.is_empty() {
-        let actual_secrets = match activity_fly_http::secrets::list(app_name) {
-            Ok(actual_secrets) => actual_secrets
-                .into_iter()
-                .map(|secret| secret.name)
-                .collect(),
-            Err(_) => {
-                bail_on_app_deletion(app_name)?;
-                HashSet::default()
-            }
-        };
-        if required_secrets.is_subset(&actual_secrets) {
-            break;
-        }
-        workflow_support::sleep(ScheduleAt::In(SchedulingDuration::Seconds(
-            SLEEP_BETWEEN_RETRIES.as_secs(),
-        )));
+    for (idx, extra_file) in extra_files.iter().enumerate() {
+        run_file_script(
+            act,
+            app_name,
+            machine_id,
+            "restore-file",
+            RESTORE_FILE_SCRIPT,
+            &extra_file.path,
+            &extra_file_backup_path(idx),
+        )?;
     }
     Ok(())
 }
 
-fn minio_start(app_name: &str) -> Result<String, AppInitModifyError> {
-    let machine_id = activity_fly_http::machines::create(
+fn run_file_script(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    step: &str,
+    script: &str,
+    path: &str,
+    backup_path: &str,
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
         app_name,
-        MINIO_VM_NAME,
-        &MachineConfig {
-            image: MINIO_IMAGE.to_string(),
-            guest: Some(GuestConfig {
-                cpu_kind: Some(CpuKind::Shared),
-                cpus: Some(1),
-                memory_mb: Some(256),
-                kernel_args: None,
-            }),
-            auto_destroy: None,
-            init: Some(InitConfig {
-                cmd: Some(
-                    "server /data --console-address :9001"
-                        .split(' ')
-                        .map(ToString::to_string)
-                        .collect(),
-                ),
-                entrypoint: None,
-                exec: None,
-                kernel_args: None,
-                swap_size_mb: None,
-                tty: None,
-            }),
-            env: None,
-            restart: Some(MachineRestart {
-                max_retries: Some(MAX_VM_FAILURE_RETRIES),
-                policy: RestartPolicy::OnFailure,
-            }),
-            stop_config: None,
-            mounts: None,
-            services: None,
-        },
-        Some(REGION),
+        machine_id,
+        step,
+        vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            script.to_string(),
+            "sh".to_string(),
+            path.to_string(),
+            backup_path.to_string(),
+        ],
     )
-    .map_err(AppInitModifyError::MinioVmError)?;
-    wait_until_started(app_name, &machine_id)?;
-    Ok(machine_id)
+    .map(|_| ())
+    .map_err(AppInitModifyError::VolumeWriteError)
 }
 
-fn minio_configure(app_name: &str, machine_id: &str) -> Result<(), AppInitModifyError> {
-    let exec = |command: &str| {
-        let exec_response = activity_fly_http::machines::exec(
-            app_name,
-            machine_id,
-            &command
-                .split(' ')
-                .map(ToString::to_string)
-                .collect::<Vec<_>>(),
-        )
-        .map_err(AppInitModifyError::MinioVmError)?;
-        assert_eq!(0, exec_response.exit_code.unwrap());
-        Ok(())
-    };
-    exec("mc alias set myminio http://127.0.0.1:9000 minioadmin minioadmin")?;
-    exec(&format!("mc mb myminio/{MINIO_BUCKET_NAME}"))?;
-    exec("mc ls myminio --json")?;
-    Ok(())
+/// Put `obelisk.toml`, the extra files, Litestream and its configuration on the volume.
+fn write_volume_files(
+    act: &impl Activities,
+    app_name: &str,
+    temp_vm_id: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<(), AppInitModifyError> {
+    let litestream_sha256 = options.litestream_sha256()?;
+    write_file(act, app_name, temp_vm_id, OBELISK_TOML_PATH, obelisk_toml)?;
+    write_extra_files(act, app_name, temp_vm_id, extra_files)?;
+    write_file(
+        act,
+        app_name,
+        temp_vm_id,
+        LITESTREAM_YML_PATH,
+        &serialize_litestream_yml(),
+    )?;
+    install_litestream(act, app_name, temp_vm_id, litestream_sha256)
 }
 
-fn start_final_vm(app_name: &str) -> Result<(), AppInitModifyError> {
-    let machine_id = activity_fly_http::machines::create(
+fn setup_volume(
+    act: &impl Activities,
+    app_name: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<(), AppInitModifyError> {
+    let volume_id = create_volume(act, app_name, VOLUME_NAME, options)?;
+    prepare_volume(
+        act,
         app_name,
-        VM_NAME_FINAL,
-        &MachineConfig {
-            image: FINAL_IMAGE.to_string(),
-            guest: Some(GuestConfig {
-                cpu_kind: Some(CpuKind::Shared),
-                cpus: Some(1),
-                memory_mb: Some(256),
-                kernel_args: None,
-            }),
-            auto_destroy: None,
-            init: Some(InitConfig {
-                cmd: Some(
-                    vec!["server", "run", "--config", "/volume/obelisk.toml"]
-                        .into_iter()
-                        .map(ToString::to_string)
-                        .collect(),
-                ),
-                entrypoint: None,
-                exec: None,
-                kernel_args: None,
-                swap_size_mb: Some(256),
-                tty: None,
-            }),
-            env: None,
-            restart: Some(MachineRestart {
-                max_retries: Some(MAX_VM_FAILURE_RETRIES),
-                policy: RestartPolicy::OnFailure,
-            }),
-            stop_config: None,
-            mounts: Some(vec![Mount {
-                volume: VOLUME_NAME.to_string(),
-                path: VOLUME_MOUNT_PATH.to_string(),
-            }]),
-            services: Some(vec![
-                // Expose health check server as https://[::]:HEALTHCHECK_EXTERNAL_PORT
-                ServiceConfig {
-                    internal_port: HEALTHCHECK_INTERNAL_PORT,
-                    protocol: ServiceProtocol::Tcp,
-                    ports: vec![PortConfig {
-                        port: HEALTHCHECK_EXTERNAL_PORT,
-                        handlers: vec![PortHandler::Tls],
-                    }],
-                },
-                // expose webhook server as default https
-                ServiceConfig {
-                    internal_port: WEBHOOK_INTERNAL_PORT,
-                    protocol: ServiceProtocol::Tcp,
-                    ports: vec![PortConfig {
-                        port: 443,
-                        handlers: vec![PortHandler::Tls],
-                    }],
-                },
-            ]),
-        },
-        Some(REGION),
+        &volume_id,
+        obelisk_toml,
+        extra_files,
+        options,
     )
-    .map_err(AppInitModifyError::FinalVmError)?;
-    wait_until_started(app_name, &machine_id)?;
+}
+
+/// Write files onto an existing volume using a temporary VM.
+fn prepare_volume(
+    act: &impl Activities,
+    app_name: &str,
+    volume_id: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<(), AppInitModifyError> {
+    let temp_vm_id = start_temp_vm(act, app_name, volume_id, options, None)?;
+    write_volume_files(
+        act,
+        app_name,
+        &temp_vm_id,
+        obelisk_toml,
+        extra_files,
+        options,
+    )?;
+    // Download WASM Components, verify configuration.
+    verify_obelisk_toml(act, app_name, &temp_vm_id)?;
+    delete_temp_vm(act, app_name, &temp_vm_id)?;
     Ok(())
 }
 
-/// Sleep until the health check passes, observing the deadline, or the app is deleted.
-fn check_health(app_name: &str, health_check_deadline_secs: u16) -> Result<(), AppInitModifyError> {
-    let start_secs = workflow_support::sleep(ScheduleAt::Now).seconds;
-    let url = format!("https://{app_name}.fly.dev:{HEALTHCHECK_EXTERNAL_PORT}");
-    loop {
-        if let Ok(http_get::Response {
-            status_code,
-            body: _,
-        }) = http_get::get_resp(&url)
-            && (200..300).contains(&status_code)
-        {
-            return Ok(());
-        }
-        bail_on_app_deletion(app_name)?;
-        let current_secs = workflow_support::sleep(ScheduleAt::In(SchedulingDuration::Seconds(
-            SLEEP_BETWEEN_RETRIES.as_secs(),
-        )))
-        .seconds;
-        if current_secs - start_secs > health_check_deadline_secs as u64 {
-            return Err(AppInitModifyError::HealthCheckFailed);
-        }
-    }
+fn litestream_restore(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+) -> Result<(), AppInitModifyError> {
+    exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "litestream-restore",
+        vec![
+            LITESTREAM_BIN_PATH.to_string(),
+            "restore".to_string(),
+            "-config".to_string(),
+            LITESTREAM_YML_PATH.to_string(),
+            SQLITE_FILE_PATH.to_string(),
+        ],
+    )
+    .map(|_| ())
+    .map_err(AppInitModifyError::LitestreamRestoreError)
 }
 
-fn cleanup(
+/// Create a new volume and restore the database from the Litestream replica.
+/// On failure the new volume is deleted, the volume in use is never touched.
+fn restore_volume(
+    act: &impl Activities,
     app_name: &str,
-    modify_error: AppInitModifyError,
-    skip_cleanup_on_error: bool,
-) -> AppInitError {
-    if skip_cleanup_on_error
-        || matches!(
-            modify_error,
-            AppInitModifyError::AppNameGetError
-                | AppInitModifyError::AppNameConflict
-                | AppInitModifyError::AppDeleted
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<String, AppInitModifyError> {
+    let env = litestream_env(act, app_name).map_err(AppInitModifyError::LitestreamRestoreError)?;
+    let volume_id = create_volume(act, app_name, VOLUME_NAME, options)?;
+    // Both volumes are named the same, mount the new one by its ID.
+    let res = start_temp_vm(act, app_name, &volume_id, options, Some(env)).and_then(|temp_vm_id| {
+        let res = write_volume_files(
+            act,
+            app_name,
+            &temp_vm_id,
+            obelisk_toml,
+            extra_files,
+            options,
         )
-    {
-        return AppInitError::CleanupNotRequired;
-    }
-    // Delete the app with force.
-    match activity_fly_http::apps::delete(app_name, true) {
-        Ok(()) => AppInitError::CleanupOk,
-        Err(cleanup_error) => AppInitError::CleanupFailed(AppCleanupFailed {
-            modify_error,
-            cleanup_error,
-        }),
+        .and_then(|()| litestream_restore(act, app_name, &temp_vm_id))
+        .and_then(|()| verify_obelisk_toml(act, app_name, &temp_vm_id));
+        delete_temp_vm(act, app_name, &temp_vm_id)?;
+        res
+    });
+    if let Err(err) = res {
+        let _ = act.volumes_delete(app_name, &volume_id);
+        return Err(err);
     }
+    Ok(volume_id)
 }
 
-fn app_create(org_slug: &str, app_name: &str) -> Result<(), AppInitModifyError> {
-    // Create the app
-    // If the app already exists, fail with AppNameConflict
-    if activity_fly_http::apps::get(app_name)
-        .map_err(|_| AppInitModifyError::AppNameGetError)?
-        .is_some()
-    {
-        return Err(AppInitModifyError::AppNameConflict);
-    }
-    // Create the app
-    activity_fly_http::apps::put(org_slug, app_name).map_err(AppInitModifyError::AppCreateError)?;
-    Ok(())
+/// Update the final VM in place to mount the given volume.
+/// The previous volume is kept, see [`switch_to_restored_volume`].
+fn swap_volume(
+    act: &impl Activities,
+    app_name: &str,
+    volume_id: &str,
+) -> Result<(), AppInitModifyError> {
+    update_final_vm(act, app_name, |machine_config| {
+        machine_config.mounts = Some(vec![Mount {
+            volume: volume_id.to_string(),
+            path: VOLUME_MOUNT_PATH.to_string(),
+        }]);
+    })
+    .map(|_| ())
 }
 
-impl Guest for Component {
-    fn prepare(
-        org_slug: String,
-        app_name: String,
-        config: ObeliskConfig,
-    ) -> Result<(), AppInitModifyError> {
-        // Check that we can serialize the configuration first.
-        // A panic is translated to `app-init-modify-error::execution-failed`
-        let obelisk_toml = serialize_obelisk_toml(&config).unwrap();
-        app_create(&org_slug, &app_name)?;
-        // Allocate an IPv6 address first.
-        allocate_ip(&app_name)?;
-        // Put `obelisk.toml`, downloaded WASM files and codegen cache on a new volume.
-        setup_volume(&app_name, &obelisk_toml)?;
-        Ok(())
-    }
+/// ID of the `db` volume attached to the final VM.
+fn final_vm_volume_id(act: &impl Activities, app_name: &str) -> Result<String, AppInitModifyError> {
+    let machine_id = get_final_vm_id(act, app_name)?;
+    act.volumes_list(app_name)
+        .map_err(fly_error(AppInitModifyError::FinalVmError, "list-volumes"))?
+        .into_iter()
+        .find(|volume| {
+            volume.name == VOLUME_NAME
+                && volume.attached_machine_id.as_deref() == Some(machine_id.as_str())
+        })
+        .map(|volume| volume.id)
+        .ok_or_else(|| {
+            AppInitModifyError::FinalVmError(
+                StepError::not_found(
+                    "get-final-vm-volume",
+                    "no volume is attached to the final VM",
+                )
+                .with_machine_id(&machine_id),
+            )
+        })
+}
 
-    fn wait_for_secrets(app_name: String, config: ObeliskConfig) -> Result<(), AppInitModifyError> {
-        let required_secrets = get_secret_keys(config);
-        wait_for_secrets(&app_name, required_secrets)?;
-        Ok(())
-    }
+fn delete_volume(
+    act: &impl Activities,
+    app_name: &str,
+    volume_id: &str,
+) -> Result<(), AppInitModifyError> {
+    act.volumes_delete(app_name, volume_id).map_err(fly_error(
+        AppInitModifyError::VolumeDeleteError,
+        "delete-volume",
+    ))
+}
 
-    fn minio_start(app_name: String) -> Result<String, AppInitModifyError> {
-        minio_start(&app_name)
+/// Switch the final VM to the restored volume and delete the superseded one once healthy,
+/// so that a recreated final VM cannot mount the pre-restore volume by name.
+/// If switching or the health check fails, the final VM is switched back and the restored volume is deleted.
+fn switch_to_restored_volume(
+    act: &impl Activities,
+    app_name: &str,
+    restored_volume_id: &str,
+    swap: impl Fn(&str) -> Result<(), AppInitModifyError>,
+    check_health: impl FnOnce() -> Result<(), AppInitModifyError>,
+) -> Result<(), RestoreError> {
+    let previous_volume_id = final_vm_volume_id(act, app_name).map_err(|err| {
+        let _ = act.volumes_delete(app_name, restored_volume_id);
+        RestoreError::RestoreFailed(err)
+    })?;
+    if let Err(swap_error) = swap(restored_volume_id).and_then(|()| check_health()) {
+        let rollback = swap(&previous_volume_id)
+            .and_then(|()| delete_volume(act, app_name, restored_volume_id));
+        return Err(match rollback {
+            Ok(()) => RestoreError::SwapFailed(swap_error),
+            Err(rollback_error) => RestoreError::RollbackFailed(RestoreRollbackFailed {
+                swap_error,
+                rollback_error,
+                restored_volume_id: restored_volume_id.to_string(),
+                previous_volume_id,
+            }),
+        });
     }
+    delete_volume(act, app_name, &previous_volume_id).map_err(|delete_error| {
+        RestoreError::PreviousVolumeKept(KeptVolume {
+            volume_id: previous_volume_id,
+            delete_error,
+        })
+    })
+}
 
-    fn minio_configure(app_name: String, machine_id: String) -> Result<(), AppInitModifyError> {
-        minio_configure(&app_name, &machine_id)
-    }
+fn get_final_vm(act: &impl Activities, app_name: &str) -> Result<Machine, AppInitModifyError> {
+    let machine_id = get_final_vm_id(act, app_name)?;
+    act.machines_get(app_name, &machine_id)
+        .map_err(fly_error(AppInitModifyError::FinalVmError, "get-final-vm"))?
+        .ok_or_else(|| {
+            AppInitModifyError::FinalVmError(StepError::not_found(
+                "get-final-vm",
+                "cannot find the final VM",
+            ))
+        })
+}
 
-    fn start_final_vm(app_name: String) -> Result<(), AppInitModifyError> {
-        start_final_vm(&app_name)
-    }
+/// Modify the configuration of the final VM in place, returning the previous configuration.
+/// Fly.io restarts the VM with the new configuration.
+fn update_final_vm(
+    act: &impl Activities,
+    app_name: &str,
+    modify: impl FnOnce(&mut MachineConfig),
+) -> Result<MachineConfig, AppInitModifyError> {
+    let machine = get_final_vm(act, app_name)?;
+    let machine_id = machine.id.clone();
+    let mut machine_config = machine.config.clone();
+    modify(&mut machine_config);
+    act.machines_update(app_name, &machine_id, &machine_config, Some(machine.region))
+        .map_err(|err| {
+            AppInitModifyError::FinalVmError(
+                StepError::fly("update-final-vm", err).with_machine_id(&machine_id),
+            )
+        })?;
+    wait_until_started(act, app_name, &machine_id, AppInitModifyError::FinalVmError)?;
+    Ok(machine.config)
+}
 
-    fn wait_for_health_check(
-        app_name: String,
-        health_check_deadline_secs: u16,
-    ) -> Result<(), AppInitModifyError> {
-        check_health(&app_name, health_check_deadline_secs)?;
-        Ok(())
+/// Record the secret digests of a deployed app, retrying a few times.
+/// Failures stay visible in the execution log, but are not fatal: `sync-secrets` records
+/// the digests on its first comparison when they are missing.
+fn record_secret_digests(
+    act: &impl Activities,
+    store: impl Fn() -> Result<(), AppInitModifyError>,
+) {
+    for attempt in 1..=MAX_SECRET_DIGESTS_ATTEMPTS {
+        if store().is_ok() {
+            return;
+        }
+        if attempt < MAX_SECRET_DIGESTS_ATTEMPTS {
+            act.sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+                SLEEP_BETWEEN_RETRIES.as_secs(),
+            )));
+        }
     }
+}
 
-    fn app_init(
-        org_slug: String,
-        app_name: String,
-        config: ObeliskConfig,
-        health_check_deadline_secs: u16,
-        skip_cleanup_on_error: bool,
-    ) -> Result<(), AppInitError> {
-        // Launch sub-workflows by using import.
-        // In case of any error including a trap (panic), delete the whole app.
-        workflow_import::prepare(&org_slug, &app_name, &config)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
-
-        workflow_import::wait_for_secrets(&app_name, &config)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
-
-        let minio_vm_id = workflow_import::minio_start(&app_name)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
-        workflow_import::minio_configure(&app_name, &minio_vm_id)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
+fn bail_on_app_deletion(act: &impl Activities, app_name: &str) -> Result<(), AppInitModifyError> {
+    match act.apps_get(app_name) {
+        Ok(None) => Err(AppInitModifyError::AppDeleted),
+        _ => Ok(()),
+    }
+}
 
-        workflow_import::start_final_vm(&app_name)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
+// Sleep until all requested secrets are stored in the app, the app is deleted or the deadline passes.
+/// Name of the join set recording a wait, e.g. `3-missing-secrets-GITHUB_TOKEN-OPENAI_API_KEY`.
+/// When the names do not fit, the count is recorded along with the names that fit,
+/// e.g. `3-missing-5-secrets-GITHUB_TOKEN`.
+fn missing_secrets_join_set_name(attempt: u32, missing: &[String]) -> String {
+    let name = format!("{attempt}-missing-secrets-{}", missing.join("-"));
+    if name.chars().count() <= MAX_JOIN_SET_NAME_LEN {
+        return name;
+    }
+    let mut name = format!("{attempt}-missing-{}-secrets", missing.len());
+    for (idx, secret) in missing.iter().enumerate() {
+        // The first name is kept even if it gets truncated.
+        if idx > 0 && name.chars().count() + 1 + secret.chars().count() > MAX_JOIN_SET_NAME_LEN {
+            break;
+        }
+        name.push('-');
+        name.push_str(secret);
+    }
+    name
+}
 
-        workflow_import::wait_for_health_check(&app_name, health_check_deadline_secs)
-            .map_err(|err| cleanup(&app_name, err, skip_cleanup_on_error))?;
+fn wait_for_secrets(
+    act: &impl Activities,
+    app_name: &str,
+    required_secrets: HashSet<String>,
+    deadline_secs: Option<u32>,
+) -> Result<(), AppInitModifyError> {
+    if required_secrets.is_empty() {
+        return Ok(());
+    }
+    let start_secs = act.sleep(ScheduleAt::Now).seconds;
+    for attempt in 1.. {
+        let (actual_secrets, list_error) = match act.secrets_list(app_name) {
+            Ok(actual_secrets) => (
+                actual_secrets
+                    .into_iter()
+                    .map(|secret| secret.name)
+                    .collect(),
+                None,
+            ),
+            Err(err) => {
+                bail_on_app_deletion(act, app_name)?;
+                (
+                    HashSet::default(),
+                    Some(StepError::fly("list-secrets", err)),
+                )
+            }
+        };
+        let mut missing: Vec<_> = required_secrets
+            .difference(&actual_secrets)
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        missing.sort();
+        let current_secs = act
+            .sleep_named(
+                &missing_secrets_join_set_name(attempt, &missing),
+                ScheduleAt::In(SchedulingDuration::Seconds(SLEEP_BETWEEN_RETRIES.as_secs())),
+            )
+            .seconds;
+        if let Some(deadline_secs) = deadline_secs
+            && current_secs - start_secs > u64::from(deadline_secs)
+        {
+            return Err(AppInitModifyError::SecretsTimeout(SecretsTimeout {
+                missing,
+                list_error,
+            }));
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
+fn minio_machine_config(options: &DeploymentOptions, env: Vec<(String, String)>) -> MachineConfig {
+    let vm_options = options.minio_vm();
+    MachineConfig {
+        image: options.minio_image().to_string(),
+        guest: Some(vm_options.guest_config()),
+        auto_destroy: None,
+        init: Some(InitConfig {
+            cmd: Some(
+                formatcp!("server {MINIO_DATA_PATH} --console-address :9001")
+                    .split(' ')
+                    .map(ToString::to_string)
+                    .collect(),
+            ),
+            entrypoint: None,
+            exec: None,
+            kernel_args: None,
+            swap_size_mb: vm_options.swap_size_mb,
+            tty: None,
+        }),
+        env: Some(env),
+        restart: Some(MachineRestart {
+            max_retries: Some(MAX_VM_FAILURE_RETRIES),
+            policy: RestartPolicy::OnFailure,
+        }),
+        stop_config: None,
+        mounts: Some(vec![Mount {
+            volume: MINIO_VOLUME_NAME.to_string(),
+            path: MINIO_DATA_PATH.to_string(),
+        }]),
+        services: None,
     }
 }
 
-fn get_secret_keys(config: ObeliskConfig) -> HashSet<String> {
-    let a_iter = config
-        .activity_wasm_list
-        .into_iter()
-        .flatten()
-        .flat_map(|component| component.env_vars)
-        .flatten()
-        .filter(|env_var| !env_var.contains("="));
-    let w_iter = config
-        .webhook_endpoint_list
+fn minio_start(
+    act: &impl Activities,
+    app_name: &str,
+    options: &DeploymentOptions,
+) -> Result<String, AppInitModifyError> {
+    // Every deployment gets its own credentials, stored in the environment of the MinIO VM.
+    let env = vec![
+        (
+            MINIO_ROOT_USER_ENV.to_string(),
+            act.random_string(MINIO_CREDENTIAL_LENGTH, MINIO_CREDENTIAL_LENGTH + 1),
+        ),
+        (
+            MINIO_ROOT_PASSWORD_ENV.to_string(),
+            act.random_string(MINIO_CREDENTIAL_LENGTH, MINIO_CREDENTIAL_LENGTH + 1),
+        ),
+    ];
+    let machine_id = act
+        .machines_create(
+            app_name,
+            MINIO_VM_NAME,
+            &minio_machine_config(options, env),
+            Some(options.region()),
+        )
+        .map_err(fly_error(AppInitModifyError::MinioVmError, "minio-start"))?;
+    wait_until_started(act, app_name, &machine_id, AppInitModifyError::MinioVmError)?;
+    Ok(machine_id)
+}
+
+fn minio_configure(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+) -> Result<(), AppInitModifyError> {
+    let machine = act
+        .machines_get(app_name, machine_id)
+        .map_err(|err| {
+            AppInitModifyError::MinioVmError(
+                StepError::fly("minio-configure", err).with_machine_id(machine_id),
+            )
+        })?
+        .ok_or_else(|| {
+            AppInitModifyError::MinioVmError(StepError::not_found(
+                "minio-configure",
+                "cannot find the MinIO VM",
+            ))
+        })?;
+    let (root_user, root_password) =
+        minio_credentials(&machine).map_err(AppInitModifyError::MinioVmError)?;
+    let exec = |command: &str| {
+        exec_checked(
+            act,
+            app_name,
+            machine_id,
+            "minio-configure",
+            command.split(' ').map(ToString::to_string).collect(),
+        )
+        .map_err(AppInitModifyError::MinioVmError)
+    };
+    exec(&format!(
+        "mc alias set myminio http://127.0.0.1:{MINIO_API_PORT} {root_user} {root_password}"
+    ))?;
+    exec(&format!(
+        "mc mb --ignore-existing myminio/{MINIO_BUCKET_NAME}"
+    ))?;
+    exec("mc ls myminio --json")?;
+    Ok(())
+}
+
+/// Root user and password generated by `minio_start`.
+fn minio_credentials(machine: &Machine) -> Result<(String, String), StepError> {
+    let get = |key: &str| {
+        machine
+            .config
+            .env
+            .iter()
+            .flatten()
+            .find(|(env_key, _)| env_key == key)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| {
+                StepError::not_found("minio-credentials", &format!("MinIO VM has no {key}"))
+                    .with_machine_id(&machine.id)
+            })
+    };
+    Ok((get(MINIO_ROOT_USER_ENV)?, get(MINIO_ROOT_PASSWORD_ENV)?))
+}
+
+/// Environment used by Litestream for accessing MinIO over the private network.
+fn litestream_env(
+    act: &impl Activities,
+    app_name: &str,
+) -> Result<Vec<(String, String)>, StepError> {
+    let minio_vm = act
+        .machines_list(app_name)
+        .map_err(|err| StepError::fly("list-machines", err))?
         .into_iter()
+        .find(|machine| machine.name == MINIO_VM_NAME)
+        .ok_or_else(|| StepError::not_found("litestream-env", "cannot find the MinIO VM"))?;
+    let (root_user, root_password) = minio_credentials(&minio_vm)?;
+    let minio_vm_id = minio_vm.id;
+    Ok(vec![
+        (
+            "LITESTREAM_ENDPOINT".to_string(),
+            format!("http://{minio_vm_id}.vm.{app_name}.internal:{MINIO_API_PORT}"),
+        ),
+        ("LITESTREAM_ACCESS_KEY_ID".to_string(), root_user),
+        ("LITESTREAM_SECRET_ACCESS_KEY".to_string(), root_password),
+    ])
+}
+
+/// Environment of the final VM: literals from the configuration followed by the Litestream settings.
+fn final_vm_env(
+    act: &impl Activities,
+    app_name: &str,
+    machine_env: &[(String, String)],
+) -> Result<Vec<(String, String)>, StepError> {
+    let mut env = machine_env.to_vec();
+    env.extend(litestream_env(act, app_name)?);
+    Ok(env)
+}
+
+/// Port of the app's public IP address, `None` if the server is reachable only over the private network.
+fn external_port(http_server: &HttpServer) -> Option<u16> {
+    match http_server.exposure {
+        HttpExposure::PublicTls => Some(http_server.external_port.unwrap_or(443)),
+        HttpExposure::PublicHttp => Some(http_server.external_port.unwrap_or(80)),
+        HttpExposure::PrivateOnly => None,
+    }
+}
+
+/// Services of the Fly.io proxy: the health check server and the public HTTP servers.
+fn final_vm_services(http_servers: &[HttpServer]) -> Vec<ServiceConfig> {
+    // Expose health check server as https://[::]:HEALTHCHECK_EXTERNAL_PORT
+    let healthcheck = ServiceConfig {
+        internal_port: HEALTHCHECK_INTERNAL_PORT,
+        protocol: ServiceProtocol::Tcp,
+        ports: vec![PortConfig {
+            port: HEALTHCHECK_EXTERNAL_PORT,
+            handlers: vec![PortHandler::Tls],
+        }],
+    };
+    let public = http_servers.iter().filter_map(|http_server| {
+        let handler = match http_server.exposure {
+            HttpExposure::PublicTls => PortHandler::Tls,
+            HttpExposure::PublicHttp => PortHandler::Http,
+            // The private network reaches the VM directly, not through the proxy.
+            HttpExposure::PrivateOnly => return None,
+        };
+        Some(ServiceConfig {
+            internal_port: http_server.internal_port,
+            protocol: ServiceProtocol::Tcp,
+            ports: vec![PortConfig {
+                port: external_port(http_server)?,
+                handlers: vec![handler],
+            }],
+        })
+    });
+    std::iter::once(healthcheck).chain(public).collect()
+}
+
+fn final_machine_config(
+    options: &DeploymentOptions,
+    env: Vec<(String, String)>,
+    http_servers: &[HttpServer],
+) -> MachineConfig {
+    let vm_options = options.obelisk_vm();
+    MachineConfig {
+        image: options.obelisk_image().to_string(),
+        guest: Some(vm_options.guest_config()),
+        auto_destroy: None,
+        init: Some(InitConfig {
+            cmd: None,
+            // Litestream runs Obelisk as a subprocess and exits when it exits.
+            entrypoint: Some(
+                vec![
+                    LITESTREAM_BIN_PATH,
+                    "replicate",
+                    "-config",
+                    LITESTREAM_YML_PATH,
+                    "-exec",
+                    formatcp!("{OBELISK_BIN_PATH} server run --config {OBELISK_TOML_PATH}"),
+                ]
+                .into_iter()
+                .map(ToString::to_string)
+                .collect(),
+            ),
+            exec: None,
+            kernel_args: None,
+            swap_size_mb: vm_options.swap_size_mb,
+            tty: None,
+        }),
+        env: Some(env),
+        restart: Some(MachineRestart {
+            max_retries: Some(MAX_VM_FAILURE_RETRIES),
+            policy: RestartPolicy::OnFailure,
+        }),
+        stop_config: None,
+        mounts: Some(vec![Mount {
+            volume: VOLUME_NAME.to_string(),
+            path: VOLUME_MOUNT_PATH.to_string(),
+        }]),
+        services: Some(final_vm_services(http_servers)),
+    }
+}
+
+fn start_final_vm(
+    act: &impl Activities,
+    app_name: &str,
+    options: &DeploymentOptions,
+    settings: &FinalVmSettings,
+) -> Result<(), AppInitModifyError> {
+    let env = final_vm_env(act, app_name, &settings.machine_env)
+        .map_err(AppInitModifyError::FinalVmError)?;
+    let machine_id = act
+        .machines_create(
+            app_name,
+            VM_NAME_FINAL,
+            &final_machine_config(options, env, &settings.http_servers),
+            Some(options.region()),
+        )
+        .map_err(fly_error(
+            AppInitModifyError::FinalVmError,
+            "start-final-vm",
+        ))?;
+    wait_until_started(act, app_name, &machine_id, AppInitModifyError::FinalVmError)?;
+    Ok(())
+}
+
+fn find_machine_id(
+    act: &impl Activities,
+    app_name: &str,
+    machine_name: &str,
+) -> Result<Option<String>, String> {
+    Ok(act
+        .machines_list(app_name)?
+        .into_iter()
+        .find(|machine| machine.name == machine_name)
+        .map(|machine| machine.id))
+}
+
+fn get_final_vm_id(act: &impl Activities, app_name: &str) -> Result<String, AppInitModifyError> {
+    bail_on_app_deletion(act, app_name)?;
+    find_machine_id(act, app_name, VM_NAME_FINAL)
+        .map_err(fly_error(AppInitModifyError::FinalVmError, "list-machines"))?
+        .ok_or_else(|| {
+            AppInitModifyError::FinalVmError(StepError::not_found(
+                "get-final-vm",
+                "cannot find the final VM",
+            ))
+        })
+}
+
+/// Replace `obelisk.toml` and place the extra files, keeping the previous `obelisk.toml` on failure.
+fn update_config(
+    act: &impl Activities,
+    app_name: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+) -> Result<(), AppInitModifyError> {
+    // The volume can only be attached to a single VM, use the running final VM.
+    let machine_id = get_final_vm_id(act, app_name)?;
+    copy_file(
+        act,
+        app_name,
+        &machine_id,
+        OBELISK_TOML_PATH,
+        OBELISK_TOML_BACKUP_PATH,
+    )?;
+    backup_extra_files(act, app_name, &machine_id, extra_files)?;
+    let res = write_file(act, app_name, &machine_id, OBELISK_TOML_PATH, obelisk_toml)
+        .and_then(|()| verify_obelisk_toml(act, app_name, &machine_id))
+        .and_then(|()| write_extra_files(act, app_name, &machine_id, extra_files));
+    if let Err(err) = res {
+        // Leave the previous configuration in place.
+        rollback_config(act, app_name, extra_files)?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Apply the environment and the services derived from the configuration to the final VM.
+fn apply_final_vm_settings(
+    act: &impl Activities,
+    app_name: &str,
+    settings: &FinalVmSettings,
+) -> Result<MachineConfig, AppInitModifyError> {
+    let env = final_vm_env(act, app_name, &settings.machine_env)
+        .map_err(AppInitModifyError::FinalVmError)?;
+    update_final_vm(act, app_name, |machine_config| {
+        machine_config.env = Some(env);
+        machine_config.services = Some(final_vm_services(&settings.http_servers));
+    })
+}
+
+fn rollback_config(
+    act: &impl Activities,
+    app_name: &str,
+    extra_files: &[ExtraFile],
+) -> Result<(), AppInitModifyError> {
+    let machine_id = get_final_vm_id(act, app_name)?;
+    copy_file(
+        act,
+        app_name,
+        &machine_id,
+        OBELISK_TOML_BACKUP_PATH,
+        OBELISK_TOML_PATH,
+    )?;
+    restore_extra_files(act, app_name, &machine_id, extra_files)
+}
+
+fn restart_final_vm(act: &impl Activities, app_name: &str) -> Result<(), AppInitModifyError> {
+    let machine_id = get_final_vm_id(act, app_name)?;
+    act.machines_restart(app_name, &machine_id).map_err(|err| {
+        AppInitModifyError::FinalVmError(
+            StepError::fly("restart-final-vm", err).with_machine_id(&machine_id),
+        )
+    })?;
+    wait_until_started(act, app_name, &machine_id, AppInitModifyError::FinalVmError)?;
+    Ok(())
+}
+
+/// Destroy the final VM and create a fresh machine from its configuration.
+/// The volume cannot leave its host, so the new machine is placed on the same host.
+fn replace_final_vm(act: &impl Activities, app_name: &str) -> Result<(), AppInitModifyError> {
+    let machine = get_final_vm(act, app_name)?;
+    let machine_id = machine.id.clone();
+    act.machines_delete(app_name, &machine_id, true)
+        .map_err(|err| {
+            AppInitModifyError::FinalVmError(
+                StepError::fly("delete-final-vm", err).with_machine_id(&machine_id),
+            )
+        })?;
+    let machine_id = act
+        .machines_create(
+            app_name,
+            VM_NAME_FINAL,
+            &machine.config,
+            Some(machine.region),
+        )
+        .map_err(fly_error(
+            AppInitModifyError::FinalVmError,
+            "replace-final-vm",
+        ))?;
+    wait_until_started(act, app_name, &machine_id, AppInitModifyError::FinalVmError)
+}
+
+fn is_endpoint_healthy(act: &impl Activities, app_name: &str) -> bool {
+    let url = format!("https://{app_name}.fly.dev:{HEALTHCHECK_EXTERNAL_PORT}");
+    matches!(
+        act.http_get_resp(&url),
+        Ok(http_get::Response { status_code, .. }) if (200..300).contains(&status_code)
+    )
+}
+
+/// Sleep until the health check passes, observing the deadline, or the app is deleted.
+fn check_health(
+    act: &impl Activities,
+    app_name: &str,
+    health_check_deadline_secs: u16,
+) -> Result<(), AppInitModifyError> {
+    let start_secs = act.sleep(ScheduleAt::Now).seconds;
+    loop {
+        if is_endpoint_healthy(act, app_name) {
+            return Ok(());
+        }
+        bail_on_app_deletion(act, app_name)?;
+        let current_secs = act
+            .sleep(ScheduleAt::In(SchedulingDuration::Seconds(
+                SLEEP_BETWEEN_RETRIES.as_secs(),
+            )))
+            .seconds;
+        if current_secs - start_secs > health_check_deadline_secs as u64 {
+            return Err(AppInitModifyError::HealthCheckFailed);
+        }
+    }
+}
+
+/// Undo what the failed `app_init` created.
+fn cleanup(
+    act: &impl Activities,
+    app_name: &str,
+    saga: Saga,
+    modify_error: AppInitModifyError,
+    skip_cleanup_on_error: bool,
+) -> AppInitError {
+    if skip_cleanup_on_error
+        || saga.is_empty()
+        || matches!(modify_error, AppInitModifyError::AppDeleted)
+    {
+        return AppInitError::CleanupNotRequired;
+    }
+    let compensations = saga.compensate(act, app_name);
+    let failed = compensations.iter().any(|result| result.error.is_some());
+    let app_cleanup = AppCleanup {
+        modify_error,
+        compensations,
+    };
+    if failed {
+        AppInitError::CleanupFailed(app_cleanup)
+    } else {
+        AppInitError::CleanupOk(app_cleanup)
+    }
+}
+
+/// Restore the previous configuration after a failed update.
+/// The previous configuration of the final VM is known if it was replaced.
+fn rollback(
+    act: &impl Activities,
+    app_name: &str,
+    config: &ObeliskConfig,
+    previous_machine_config: MachineConfig,
+    modify_error: AppInitModifyError,
+    health_check_deadline_secs: u16,
+) -> AppUpdateError {
+    let res = workflow_import::rollback_config(app_name, config)
+        .and_then(|()| {
+            update_final_vm(act, app_name, |machine_config| {
+                *machine_config = previous_machine_config;
+            })
+            .map(|_| ())
+        })
+        .and_then(|()| {
+            workflow_import::wait_for_health_check(app_name, health_check_deadline_secs)
+        });
+    match res {
+        Ok(()) => AppUpdateError::RollbackOk(modify_error),
+        Err(rollback_error) => AppUpdateError::RollbackFailed(AppRollbackFailed {
+            modify_error,
+            rollback_error,
+        }),
+    }
+}
+
+fn render_obelisk_toml(config: &ObeliskConfig) -> Result<String, AppInitModifyError> {
+    serialize_obelisk_toml(config)
+        .map_err(|err| AppInitModifyError::ConfigInvalid(format!("{err:#}")))
+}
+
+fn app_create(
+    act: &impl Activities,
+    org_slug: &str,
+    app_name: &str,
+) -> Result<(), AppInitModifyError> {
+    // Create the app
+    // If the app already exists, fail with AppNameConflict
+    if act
+        .apps_get(app_name)
+        .map_err(|_| AppInitModifyError::AppNameGetError)?
+        .is_some()
+    {
+        return Err(AppInitModifyError::AppNameConflict);
+    }
+    // Create the app
+    act.apps_put(org_slug, app_name)
+        .map_err(fly_error(AppInitModifyError::AppCreateError, "app-create"))?;
+    Ok(())
+}
+
+fn prepare(
+    act: &impl Activities,
+    org_slug: &str,
+    app_name: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<(), AppInitModifyError> {
+    app_create(act, org_slug, app_name)?;
+    // Allocate an IPv6 address first.
+    allocate_ip(act, app_name)?;
+    // Put `obelisk.toml`, downloaded WASM files and codegen cache on a new volume.
+    setup_volume(act, app_name, obelisk_toml, extra_files, options)?;
+    // MinIO keeps the Litestream replica on its own volume.
+    create_volume(act, app_name, MINIO_VOLUME_NAME, options)?;
+    Ok(())
+}
+
+impl Guest for Component {
+    fn prepare(
+        org_slug: String,
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+    ) -> Result<(), AppInitModifyError> {
+        // Check that we can serialize the configuration first.
+        let obelisk_toml = render_obelisk_toml(&config)?;
+        options.litestream_sha256()?;
+        prepare(
+            &Imported,
+            &org_slug,
+            &app_name,
+            &obelisk_toml,
+            get_extra_files(&config),
+            &options,
+        )
+    }
+
+    fn plan(
+        org_slug: String,
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+    ) -> Result<DeploymentPlan, AppInitModifyError> {
+        let obelisk_toml = render_obelisk_toml(&config)?;
+        let mut required_secrets: Vec<_> = get_secret_keys(&config).into_iter().collect();
+        required_secrets.sort();
+        plan(
+            &org_slug,
+            &app_name,
+            obelisk_toml,
+            get_extra_files(&config),
+            required_secrets,
+            &final_vm_settings(&config),
+            &options,
+        )
+    }
+
+    fn wait_for_secrets(
+        app_name: String,
+        config: ObeliskConfig,
+        deadline_secs: Option<u32>,
+    ) -> Result<(), AppInitModifyError> {
+        let required_secrets = get_secret_keys(&config);
+        wait_for_secrets(&Imported, &app_name, required_secrets, deadline_secs)?;
+        Ok(())
+    }
+
+    fn minio_start(
+        app_name: String,
+        options: DeploymentOptions,
+    ) -> Result<String, AppInitModifyError> {
+        minio_start(&Imported, &app_name, &options)
+    }
+
+    fn minio_configure(app_name: String, machine_id: String) -> Result<(), AppInitModifyError> {
+        minio_configure(&Imported, &app_name, &machine_id)
+    }
+
+    fn start_final_vm(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+    ) -> Result<(), AppInitModifyError> {
+        start_final_vm(&Imported, &app_name, &options, &final_vm_settings(&config))
+    }
+
+    fn wait_for_health_check(
+        app_name: String,
+        health_check_deadline_secs: u16,
+    ) -> Result<(), AppInitModifyError> {
+        check_health(&Imported, &app_name, health_check_deadline_secs)?;
+        Ok(())
+    }
+
+    fn store_secret_digests(
+        app_name: String,
+        config: ObeliskConfig,
+    ) -> Result<(), AppInitModifyError> {
+        store_secret_digests(&Imported, &app_name, &get_secret_users(&config))
+            .map_err(AppInitModifyError::SecretDigestsError)
+    }
+
+    fn app_init(
+        org_slug: String,
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+        health_check_deadline_secs: u16,
+        skip_cleanup_on_error: bool,
+        secrets_deadline_secs: Option<u32>,
+    ) -> Result<(), AppInitError> {
+        // Launch sub-workflows by using import.
+        // In case of any error including a trap (panic), undo every recorded step.
+        let act = &Imported;
+        let mut saga = Saga::default();
+        let res = (|| {
+            saga.step(act, &app_name, || {
+                workflow_import::prepare(&org_slug, &app_name, &config, &options)
+            })?;
+
+            workflow_import::wait_for_secrets(&app_name, &config, secrets_deadline_secs)?;
+
+            let minio_vm_id = saga.step(act, &app_name, || {
+                workflow_import::minio_start(&app_name, &options)
+            })?;
+            workflow_import::minio_configure(&app_name, &minio_vm_id)?;
+
+            saga.step(act, &app_name, || {
+                workflow_import::start_final_vm(&app_name, &config, &options)
+            })?;
+
+            workflow_import::wait_for_health_check(&app_name, health_check_deadline_secs)
+        })();
+        res.map_err(|err| cleanup(act, &app_name, saga, err, skip_cleanup_on_error))?;
+        // The app is healthy, never clean it up because of the digests.
+        record_secret_digests(act, || {
+            workflow_import::store_secret_digests(&app_name, &config)
+        });
+        Ok(())
+    }
+
+    fn resume_prepare(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+    ) -> Result<(), AppInitModifyError> {
+        let obelisk_toml = render_obelisk_toml(&config)?;
+        resume_prepare(
+            &Imported,
+            &app_name,
+            &obelisk_toml,
+            get_extra_files(&config),
+            &options,
+        )
+    }
+
+    fn app_resume(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+        health_check_deadline_secs: u16,
+        skip_cleanup_on_error: bool,
+        secrets_deadline_secs: Option<u32>,
+    ) -> Result<(), AppInitError> {
+        let act = &Imported;
+        let mut saga = Saga::default();
+        let res = (|| {
+            saga.step(act, &app_name, || {
+                workflow_import::resume_prepare(&app_name, &config, &options)
+            })?;
+
+            workflow_import::wait_for_secrets(&app_name, &config, secrets_deadline_secs)?;
+
+            let progress = inspect_progress(act, &app_name)?;
+            let minio_vm_id = match progress.minio_vm_id {
+                Some(minio_vm_id) => minio_vm_id,
+                None => saga.step(act, &app_name, || {
+                    workflow_import::minio_start(&app_name, &options)
+                })?,
+            };
+            // Configuring an already configured MinIO VM is a no-op.
+            workflow_import::minio_configure(&app_name, &minio_vm_id)?;
+
+            if !progress.final_vm {
+                saga.step(act, &app_name, || {
+                    workflow_import::start_final_vm(&app_name, &config, &options)
+                })?;
+            }
+
+            workflow_import::wait_for_health_check(&app_name, health_check_deadline_secs)
+        })();
+        res.map_err(|err| cleanup(act, &app_name, saga, err, skip_cleanup_on_error))?;
+        record_secret_digests(act, || {
+            workflow_import::store_secret_digests(&app_name, &config)
+        });
+        Ok(())
+    }
+
+    fn update_config(app_name: String, config: ObeliskConfig) -> Result<(), AppInitModifyError> {
+        let obelisk_toml = render_obelisk_toml(&config)?;
+        update_config(
+            &Imported,
+            &app_name,
+            &obelisk_toml,
+            get_extra_files(&config),
+        )
+    }
+
+    fn rollback_config(app_name: String, config: ObeliskConfig) -> Result<(), AppInitModifyError> {
+        rollback_config(&Imported, &app_name, get_extra_files(&config))
+    }
+
+    fn restart_final_vm(app_name: String) -> Result<(), AppInitModifyError> {
+        restart_final_vm(&Imported, &app_name)
+    }
+
+    fn app_update(
+        app_name: String,
+        config: ObeliskConfig,
+        health_check_deadline_secs: u16,
+    ) -> Result<(), AppUpdateError> {
+        let act = &Imported;
+        // Taken before anything changes, the update can fail after the VM was already modified.
+        let previous_machine_config = get_final_vm(act, &app_name)
+            .map_err(AppUpdateError::UpdateFailed)?
+            .config;
+        // If the new configuration cannot be verified, the running VM is left untouched.
+        workflow_import::update_config(&app_name, &config).map_err(AppUpdateError::UpdateFailed)?;
+
+        // Environment literals and HTTP servers are part of the VM's configuration.
+        apply_final_vm_settings(act, &app_name, &final_vm_settings(&config))
+            .and_then(|_| {
+                workflow_import::wait_for_health_check(&app_name, health_check_deadline_secs)
+            })
+            .map_err(|err| {
+                rollback(
+                    act,
+                    &app_name,
+                    &config,
+                    previous_machine_config,
+                    err,
+                    health_check_deadline_secs,
+                )
+            })
+    }
+
+    fn restore_volume(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+    ) -> Result<String, AppInitModifyError> {
+        let obelisk_toml = render_obelisk_toml(&config)?;
+        restore_volume(
+            &Imported,
+            &app_name,
+            &obelisk_toml,
+            get_extra_files(&config),
+            &options,
+        )
+    }
+
+    fn swap_volume(app_name: String, volume_id: String) -> Result<(), AppInitModifyError> {
+        swap_volume(&Imported, &app_name, &volume_id)
+    }
+
+    fn restore_from_backup(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+        health_check_deadline_secs: u16,
+    ) -> Result<(), RestoreError> {
+        let volume_id = workflow_import::restore_volume(&app_name, &config, &options)
+            .map_err(RestoreError::RestoreFailed)?;
+
+        switch_to_restored_volume(
+            &Imported,
+            &app_name,
+            &volume_id,
+            |volume_id| workflow_import::swap_volume(&app_name, volume_id),
+            || workflow_import::wait_for_health_check(&app_name, health_check_deadline_secs),
+        )
+    }
+
+    fn reconcile(
+        app_name: String,
+        config: ObeliskConfig,
+        options: DeploymentOptions,
+        correct: bool,
+    ) -> Result<Vec<DriftReport>, ReconcileError> {
+        reconcile(
+            &Imported,
+            &app_name,
+            &get_secret_keys(&config),
+            &final_vm_settings(&config),
+            &options,
+            correct,
+        )
+    }
+
+    fn watch(app_name: String, options: WatchOptions) -> Result<WatchReport, WatchError> {
+        watch(&Imported, &app_name, &options)
+    }
+
+    fn sync_secrets(
+        app_name: String,
+        config: ObeliskConfig,
+        options: SyncSecretsOptions,
+    ) -> Result<Vec<SecretRotation>, SyncSecretsError> {
+        sync_secrets(&Imported, &app_name, &get_secret_users(&config), &options)
+    }
+
+    fn app_destroy(
+        app_name: String,
+        options: AppDestroyOptions,
+    ) -> Result<Vec<ResourceResult>, AppDestroyError> {
+        destroy::app_destroy(&Imported, &app_name, &options)
+    }
+}
+
+fn env_vars(config: &ObeliskConfig) -> impl Iterator<Item = &EnvVar> {
+    let a_iter = config
+        .activity_wasm_list
+        .iter()
+        .flatten()
+        .flat_map(|component| component.env_vars.iter().flatten());
+    let w_iter = config
+        .webhook_endpoint_list
+        .iter()
+        .flatten()
+        .flat_map(|component| component.env_vars.iter().flatten());
+    a_iter.chain(w_iter)
+}
+
+fn get_secret_keys(config: &ObeliskConfig) -> HashSet<String> {
+    env_vars(config)
+        .filter_map(|env_var| match env_var {
+            EnvVar::Secret(key) => Some(key.clone()),
+            EnvVar::Literal(_) | EnvVar::MachineEnv(_) => None,
+        })
+        .chain(get_api_proxy(config).map(|api_proxy| api_auth_secret(api_proxy.auth).to_string()))
+        .collect()
+}
+
+/// Proxy webhook exposing the WebUI publicly, if configured.
+fn get_api_proxy(config: &ObeliskConfig) -> Option<&ApiProxy> {
+    match &config.api_exposure {
+        Some(ApiExposure::Public(api_proxy)) => Some(api_proxy),
+        Some(ApiExposure::PrivateOnly) | None => None,
+    }
+}
+
+/// Secret holding the credentials checked by the proxy webhook.
+fn api_auth_secret(auth: ApiAuth) -> &'static str {
+    match auth {
+        ApiAuth::Token => "OBELISK_API_TOKEN",
+        ApiAuth::Basic => "OBELISK_API_BASIC_AUTH",
+    }
+}
+
+fn get_extra_files(config: &ObeliskConfig) -> &[ExtraFile] {
+    config.extra_files.as_deref().unwrap_or_default()
+}
+
+/// Components referencing each secret.
+fn get_secret_users(config: &ObeliskConfig) -> BTreeMap<String, Vec<String>> {
+    let activities = config
+        .activity_wasm_list
+        .iter()
         .flatten()
-        .flat_map(|component| component.env_vars)
+        .map(|component| (&component.name, &component.env_vars));
+    let webhooks = config
+        .webhook_endpoint_list
+        .iter()
         .flatten()
-        .filter(|env_var| !env_var.contains("="));
-    a_iter.chain(w_iter).collect()
+        .map(|component| (&component.name, &component.env_vars));
+    let mut secret_users: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (component_name, env_vars) in activities.chain(webhooks) {
+        for env_var in env_vars.iter().flatten() {
+            if let EnvVar::Secret(key) = env_var {
+                secret_users
+                    .entry(key.clone())
+                    .or_default()
+                    .push(component_name.clone());
+            }
+        }
+    }
+    if let Some(api_proxy) = get_api_proxy(config) {
+        secret_users
+            .entry(api_auth_secret(api_proxy.auth).to_string())
+            .or_default()
+            .push(API_PROXY_WEBHOOK_NAME.to_string());
+    }
+    secret_users
+}
+
+/// Literals set in the final VM's environment, sorted by key.
+fn get_machine_env(config: &ObeliskConfig) -> Vec<(String, String)> {
+    let mut machine_env: Vec<_> = env_vars(config)
+        .filter_map(|env_var| match env_var {
+            EnvVar::Literal(literal) if literal.in_machine_env => {
+                Some((literal.key.clone(), literal.value.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    machine_env.sort();
+    // The same literal can be used by several components.
+    machine_env.dedup();
+    machine_env
+}
+
+/// HTTP servers of the configuration, a public `webhook_server` by default,
+/// followed by the server of the proxy webhook.
+fn get_http_servers(config: &ObeliskConfig) -> Vec<HttpServer> {
+    let mut http_servers = config.http_server_list.clone().unwrap_or_else(|| {
+        vec![HttpServer {
+            name: WEBHOOK_SERVER_NAME.to_string(),
+            internal_port: WEBHOOK_INTERNAL_PORT,
+            external_port: None,
+            exposure: HttpExposure::PublicTls,
+        }]
+    });
+    if let Some(api_proxy) = get_api_proxy(config) {
+        http_servers.push(HttpServer {
+            name: API_PROXY_SERVER_NAME.to_string(),
+            internal_port: API_PROXY_INTERNAL_PORT,
+            external_port: Some(api_proxy.external_port.unwrap_or(API_PROXY_EXTERNAL_PORT)),
+            exposure: HttpExposure::PublicTls,
+        });
+    }
+    http_servers
+}
+
+/// Settings of the final VM derived from the configuration.
+#[derive(Default)]
+struct FinalVmSettings {
+    /// Literals set in the VM's environment.
+    machine_env: Vec<(String, String)>,
+    /// HTTP servers, the public ones are exposed by the Fly.io proxy.
+    http_servers: Vec<HttpServer>,
+}
+
+fn final_vm_settings(config: &ObeliskConfig) -> FinalVmSettings {
+    FinalVmSettings {
+        machine_env: get_machine_env(config),
+        http_servers: get_http_servers(config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{APP, FakeFly, deployed, options};
+    use crate::generated::obelisk_flyio::workflow::types::{ErrorKind, ResourceKind};
+
+    /// App with both volumes created as in `prepare`.
+    fn prepared(obelisk_toml: &str) -> FakeFly {
+        let fly = FakeFly::with_app(APP);
+        setup_volume(&fly, APP, obelisk_toml, &[], &options()).unwrap();
+        create_volume(&fly, APP, MINIO_VOLUME_NAME, &options()).unwrap();
+        fly
+    }
+
+    fn volume_id(fly: &FakeFly) -> String {
+        fly.app(APP).volumes[0].id.clone()
+    }
+
+    #[test]
+    fn app_create_should_fail_on_existing_app() {
+        let fly = FakeFly::with_app(APP);
+        let err = app_create(&fly, "org", APP).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::AppNameConflict));
+    }
+
+    #[test]
+    fn app_create_should_map_errors() {
+        let fly = FakeFly::default();
+        fly.fail("apps::get", "connection reset");
+        let err = app_create(&fly, "org", APP).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::AppNameGetError));
+
+        fly.fail("apps::put", "500 Internal Server Error");
+        let err = app_create(&fly, "org", APP).unwrap_err();
+        let AppInitModifyError::AppCreateError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("app-create", step_error.step);
+        assert_eq!(Some(500), step_error.status);
+
+        app_create(&fly, "org", APP).unwrap();
+        assert!(fly.state().apps.contains_key(APP));
+    }
+
+    #[test]
+    fn allocate_ip_should_release_extra_ips() {
+        let fly = FakeFly::with_app(APP);
+        // A previous attempt allocated an IP, but its response was lost.
+        allocate_ip(&fly, APP).unwrap();
+        allocate_ip(&fly, APP).unwrap();
+        assert_eq!(1, fly.app(APP).ips.len());
+
+        fly.fail("ips::allocate-unsafe", "429 Too Many Requests");
+        let err = allocate_ip(&fly, APP).unwrap_err();
+        let AppInitModifyError::IpAllocateError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("allocate-ip", step_error.step);
+        assert_eq!(ErrorKind::RateLimited, step_error.kind);
+    }
+
+    #[test]
+    fn setup_volume_should_write_files_and_delete_temp_vm() {
+        let fly = FakeFly::with_app(APP);
+        setup_volume(&fly, APP, "obelisk.toml contents", &[], &options()).unwrap();
+        let volume_id = volume_id(&fly);
+        assert_eq!(
+            Some("obelisk.toml contents".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+        assert_eq!(
+            Some(serialize_litestream_yml()),
+            fly.file(&volume_id, LITESTREAM_YML_PATH)
+        );
+        let app = fly.app(APP);
+        assert!(app.machines.is_empty());
+        assert_eq!(None, app.volumes[0].attached_machine_id);
+    }
+
+    #[test]
+    fn setup_volume_should_place_extra_files() {
+        let fly = FakeFly::with_app(APP);
+        let extra_files = [
+            ExtraFile {
+                path: "/volume/prompts/summary.txt".to_string(),
+                contents: "Summarize $REPO\n".to_string(),
+            },
+            ExtraFile {
+                path: "/volume/ca.pem".to_string(),
+                contents: "-----BEGIN CERTIFICATE-----\n".to_string(),
+            },
+        ];
+        setup_volume(&fly, APP, "", &extra_files, &options()).unwrap();
+        let volume_id = volume_id(&fly);
+        for extra_file in &extra_files {
+            assert_eq!(
+                Some(extra_file.contents.clone()),
+                fly.file(&volume_id, &extra_file.path)
+            );
+        }
+        assert_eq!(
+            1,
+            fly.calls("machines::exec")
+                .iter()
+                .filter(|call| call.contains("mkdir -p /volume/prompts"))
+                .count()
+        );
+    }
+
+    #[test]
+    fn setup_volume_should_require_litestream_digest() {
+        let fly = FakeFly::with_app(APP);
+        let options = DeploymentOptions {
+            litestream_sha256: None,
+            ..options()
+        };
+        let err = setup_volume(&fly, APP, "", &[], &options).unwrap_err();
+        assert!(
+            matches!(err, AppInitModifyError::ConfigInvalid(_)),
+            "unexpected error {err:?}"
+        );
+        assert!(fly.calls("machines::exec").is_empty());
+
+        let options = DeploymentOptions {
+            litestream_sha256: Some("not a digest".to_string()),
+            ..options
+        };
+        let err = setup_volume(&fly, APP, "", &[], &options).unwrap_err();
+        assert!(
+            matches!(err, AppInitModifyError::ConfigInvalid(_)),
+            "unexpected error {err:?}"
+        );
+    }
+
+    #[test]
+    fn setup_volume_should_reject_tampered_litestream_download() {
+        let fly = FakeFly::with_app(APP);
+        fly.fail_exec(
+            "sha256sum -c",
+            1,
+            "sha256sum: WARNING: 1 computed checksum did NOT match",
+        );
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::VolumeWriteError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("install-litestream", step_error.step);
+        assert_eq!(ErrorKind::ExecFailed, step_error.kind);
+
+        let fly = FakeFly::with_app(APP);
+        fly.fail_exec("curl", 127, "curl not found in the image");
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::VolumeWriteError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::ExecFailed, step_error.kind);
+        assert_eq!(Some(127), step_error.exit_code);
+    }
+
+    #[test]
+    fn setup_volume_should_map_errors() {
+        let fly = FakeFly::with_app(APP);
+        fly.fail("volumes::create", "insufficient capacity");
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::VolumeCreateError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::QuotaExceeded, step_error.kind);
+
+        let fly = FakeFly::with_app(APP);
+        fly.fail("machines::create", "failed to pull image");
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::TempVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::ImagePullFailed, step_error.kind);
+
+        let fly = FakeFly::with_app(APP);
+        fly.fail("machines::get", "timed out");
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::TempVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("wait-until-started", step_error.step);
+        assert!(step_error.machine_id.is_some());
+
+        let fly = FakeFly::with_app(APP);
+        fly.fail_exec(
+            "curl",
+            22,
+            "curl: (22) The requested URL returned error: 404",
+        );
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::VolumeWriteError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("install-litestream", step_error.step);
+        assert_eq!(Some(22), step_error.exit_code);
+
+        let fly = FakeFly::with_app(APP);
+        fly.fail_exec("server verify", 1, "cannot pull the component");
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::VerifyError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::ExecFailed, step_error.kind);
+        assert_eq!(
+            Some("cannot pull the component".to_string()),
+            step_error.stderr
+        );
+    }
+
+    #[test]
+    fn wait_for_secrets_should_sleep_until_secrets_are_set() {
+        let fly = FakeFly::with_app(APP);
+        fly.set_secret_at(APP, "GITHUB_TOKEN", 25);
+        let required_secrets = HashSet::from_iter(["GITHUB_TOKEN".to_string()]);
+        wait_for_secrets(&fly, APP, required_secrets, None).unwrap();
+        assert_eq!(30, fly.state().now);
+        assert_eq!(
+            vec![
+                "sleep 1-missing-secrets-GITHUB_TOKEN",
+                "sleep 2-missing-secrets-GITHUB_TOKEN",
+                "sleep 3-missing-secrets-GITHUB_TOKEN",
+            ],
+            fly.calls("sleep")
+        );
+    }
+
+    #[test]
+    fn wait_for_secrets_should_sanitize_join_set_name() {
+        let fly = FakeFly::with_app(APP);
+        let secret = format!("API.KEY:{}", "X".repeat(100));
+        fly.set_secret_at(APP, &secret, 5);
+        wait_for_secrets(&fly, APP, HashSet::from_iter([secret]), None).unwrap();
+        let expected = format!("1-missing-1-secrets-API_KEY_{}", "X".repeat(36));
+        assert_eq!(64, expected.len());
+        assert_eq!(vec![format!("sleep {expected}")], fly.calls("sleep"));
+    }
+
+    #[test]
+    fn long_secret_names_should_be_counted() {
+        let missing: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|prefix| format!("{prefix}_{}", "X".repeat(15)))
+            .collect();
+        let name = missing_secrets_join_set_name(2, &missing);
+        assert_eq!(
+            format!("2-missing-3-secrets-{}-{}", missing[0], missing[1]),
+            name
+        );
+        assert!(name.len() <= MAX_JOIN_SET_NAME_LEN);
+        assert_eq!(
+            "2-missing-secrets-GITHUB_TOKEN-OPENAI_API_KEY",
+            missing_secrets_join_set_name(
+                2,
+                &["GITHUB_TOKEN".to_string(), "OPENAI_API_KEY".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn secret_digests_should_be_retried_without_failing() {
+        let fly = deployed("");
+        let attempts = std::cell::Cell::new(0);
+        record_secret_digests(&fly, || {
+            attempts.set(attempts.get() + 1);
+            store_secret_digests(&fly, APP, &BTreeMap::new())
+                .map_err(AppInitModifyError::SecretDigestsError)
+        });
+        assert_eq!(1, attempts.get());
+
+        attempts.set(0);
+        for _ in 0..MAX_SECRET_DIGESTS_ATTEMPTS {
+            fly.fail("machines::list", "503 Service Unavailable");
+        }
+        let start = fly.state().now;
+        let deletions = fly.calls("machines::delete").len();
+        record_secret_digests(&fly, || {
+            attempts.set(attempts.get() + 1);
+            store_secret_digests(&fly, APP, &BTreeMap::new())
+                .map_err(AppInitModifyError::SecretDigestsError)
+        });
+        assert_eq!(MAX_SECRET_DIGESTS_ATTEMPTS, attempts.get());
+        assert_eq!(
+            start + u64::from(MAX_SECRET_DIGESTS_ATTEMPTS - 1) * SLEEP_BETWEEN_RETRIES.as_secs(),
+            fly.state().now
+        );
+        // The app is left running.
+        assert_eq!(deletions, fly.calls("machines::delete").len());
+    }
+
+    #[test]
+    fn wait_for_secrets_should_observe_deadline() {
+        let fly = FakeFly::with_app(APP);
+        fly.set_secret_at(APP, "GITHUB_TOKEN", 15);
+        fly.fail("secrets::list", "503 Service Unavailable");
+        let required_secrets =
+            HashSet::from_iter(["GITHUB_TOKEN".to_string(), "OPENAI_API_KEY".to_string()]);
+        let err = wait_for_secrets(&fly, APP, required_secrets, Some(25)).unwrap_err();
+        let AppInitModifyError::SecretsTimeout(secrets_timeout) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(vec!["OPENAI_API_KEY".to_string()], secrets_timeout.missing);
+        assert!(secrets_timeout.list_error.is_none());
+        assert_eq!(
+            "sleep 1-missing-secrets-GITHUB_TOKEN-OPENAI_API_KEY",
+            fly.calls("sleep")[0]
+        );
+        assert_eq!(30, fly.state().now);
+    }
+
+    #[test]
+    fn wait_for_secrets_should_report_list_error_on_deadline() {
+        let fly = FakeFly::with_app(APP);
+        for _ in 0..3 {
+            fly.fail("secrets::list", "503 Service Unavailable");
+        }
+        let required_secrets = HashSet::from_iter(["GITHUB_TOKEN".to_string()]);
+        let err = wait_for_secrets(&fly, APP, required_secrets, Some(25)).unwrap_err();
+        let AppInitModifyError::SecretsTimeout(secrets_timeout) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(vec!["GITHUB_TOKEN".to_string()], secrets_timeout.missing);
+        let list_error = secrets_timeout.list_error.unwrap();
+        assert_eq!("list-secrets", list_error.step);
+        assert!(list_error.message.contains("503"));
+    }
+
+    #[test]
+    fn env_vars_should_be_split_into_secrets_and_machine_env() {
+        use crate::generated::obelisk_flyio::workflow::types::{ActivityWasm, EnvLiteral};
+        let literal = |key: &str, in_machine_env| {
+            EnvVar::Literal(EnvLiteral {
+                key: key.to_string(),
+                value: "value".to_string(),
+                in_machine_env,
+            })
+        };
+        let activity = |name: &str, env_vars| ActivityWasm {
+            name: name.to_string(),
+            location_oci: format!("docker.io/example/{name}:2025-10-01"),
+            env_vars: Some(env_vars),
+            lock_expiry_seconds: None,
+            max_retries: None,
+            retry_exp_backoff_ms: None,
+            retry_on_err: None,
+            max_inflight_instances: None,
+            forward_stdout: None,
+            forward_stderr: None,
+        };
+        let config = ObeliskConfig {
+            activity_wasm_list: Some(vec![
+                activity(
+                    "activity_a",
+                    vec![
+                        EnvVar::Secret("TOKEN".to_string()),
+                        literal("LOG_LEVEL", false),
+                        literal("API_URL", true),
+                    ],
+                ),
+                activity(
+                    "activity_b",
+                    vec![
+                        EnvVar::MachineEnv("FLY_REGION".to_string()),
+                        literal("API_URL", true),
+                    ],
+                ),
+            ]),
+            workflow_list: None,
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: None,
+            cron_list: None,
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        };
+        assert_eq!(
+            HashSet::from_iter(["TOKEN".to_string()]),
+            get_secret_keys(&config)
+        );
+        assert_eq!(
+            vec![("API_URL".to_string(), "value".to_string())],
+            get_machine_env(&config)
+        );
+    }
+
+    #[test]
+    fn public_api_should_require_auth_secret_and_proxy_server() {
+        let config = ObeliskConfig {
+            activity_wasm_list: None,
+            workflow_list: None,
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: Some(ApiExposure::Public(ApiProxy {
+                location_oci: "docker.io/example/webhook_api_proxy:2025-10-01".to_string(),
+                auth: ApiAuth::Basic,
+                external_port: None,
+            })),
+            activity_stub_list: None,
+            cron_list: None,
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        };
+        assert_eq!(
+            HashSet::from_iter(["OBELISK_API_BASIC_AUTH".to_string()]),
+            get_secret_keys(&config)
+        );
+        assert_eq!(
+            Some(&vec![API_PROXY_WEBHOOK_NAME.to_string()]),
+            get_secret_users(&config).get("OBELISK_API_BASIC_AUTH")
+        );
+        let exposed: Vec<_> = get_http_servers(&config)
+            .iter()
+            .map(|http_server| (http_server.name.clone(), external_port(http_server)))
+            .collect();
+        assert_eq!(
+            vec![
+                (WEBHOOK_SERVER_NAME.to_string(), Some(443)),
+                (
+                    API_PROXY_SERVER_NAME.to_string(),
+                    Some(API_PROXY_EXTERNAL_PORT)
+                ),
+            ],
+            exposed
+        );
+        // Only the WebUI is proxied, the gRPC API stays on the private network.
+        assert!(
+            get_http_servers(&config)
+                .iter()
+                .all(|http_server| http_server.internal_port != API_INTERNAL_PORT)
+        );
+    }
+
+    #[test]
+    fn wait_for_secrets_should_bail_on_app_deletion() {
+        let fly = FakeFly::default();
+        let required_secrets = HashSet::from_iter(["GITHUB_TOKEN".to_string()]);
+        let err = wait_for_secrets(&fly, APP, required_secrets, None).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::AppDeleted));
+    }
+
+    #[test]
+    fn minio_should_map_errors() {
+        let fly = prepared("");
+        fly.fail("machines::create", "500 Internal Server Error");
+        let err = minio_start(&fly, APP, &options()).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::MinioVmError(_)));
+
+        let minio_vm_id = minio_start(&fly, APP, &options()).unwrap();
+        fly.fail_exec("mc mb", 1, "Unable to make bucket");
+        let err = minio_configure(&fly, APP, &minio_vm_id).unwrap_err();
+        let AppInitModifyError::MinioVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("minio-configure", step_error.step);
+        assert_eq!(Some(minio_vm_id), step_error.machine_id);
+    }
+
+    #[test]
+    fn minio_should_use_its_volume_and_generated_credentials() {
+        let fly = deployed("");
+        let app = fly.app(APP);
+        let minio_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == MINIO_VM_NAME)
+            .unwrap();
+        let minio_volume = app
+            .volumes
+            .iter()
+            .find(|volume| volume.name == MINIO_VOLUME_NAME)
+            .unwrap();
+        assert_eq!(Some(minio_vm.id.clone()), minio_volume.attached_machine_id);
+
+        let (root_user, root_password) = minio_credentials(minio_vm).unwrap();
+        assert_eq!(MINIO_CREDENTIAL_LENGTH as usize, root_user.len());
+        assert_ne!(root_user, root_password);
+        let alias_set = fly.calls("machines::exec mc alias set");
+        assert!(alias_set[0].ends_with(&format!("{root_user} {root_password}")));
+
+        let final_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .unwrap();
+        let final_env = final_vm.config.env.clone().unwrap();
+        assert!(final_env.contains(&("LITESTREAM_ACCESS_KEY_ID".to_string(), root_user)));
+        assert!(final_env.contains(&("LITESTREAM_SECRET_ACCESS_KEY".to_string(), root_password)));
+    }
+
+    #[test]
+    fn start_final_vm_should_require_minio() {
+        let fly = prepared("");
+        let err = start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap_err();
+        let AppInitModifyError::FinalVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::NotFound, step_error.kind);
+
+        minio_start(&fly, APP, &options()).unwrap();
+        fly.fail("machines::create", "insufficient memory");
+        let err = start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap_err();
+        let AppInitModifyError::FinalVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("start-final-vm", step_error.step);
+
+        start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap();
+        let app = fly.app(APP);
+        let final_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .unwrap();
+        assert_eq!(
+            Some(final_vm.id.clone()),
+            app.volumes[0].attached_machine_id
+        );
+    }
+
+    #[test]
+    fn start_final_vm_should_expose_only_public_servers() {
+        let fly = prepared("");
+        minio_start(&fly, APP, &options()).unwrap();
+        let http_server = |name: &str, internal_port, external_port, exposure| HttpServer {
+            name: name.to_string(),
+            internal_port,
+            external_port,
+            exposure,
+        };
+        let settings = FinalVmSettings {
+            http_servers: vec![
+                http_server("webhook_server", 9090, None, HttpExposure::PublicTls),
+                http_server("plain", 9000, Some(8000), HttpExposure::PublicHttp),
+                http_server("internal", 9001, None, HttpExposure::PrivateOnly),
+            ],
+            ..FinalVmSettings::default()
+        };
+        start_final_vm(&fly, APP, &options(), &settings).unwrap();
+        let app = fly.app(APP);
+        let final_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .unwrap();
+        let exposed: Vec<_> = final_vm
+            .config
+            .services
+            .iter()
+            .flatten()
+            .map(|service| (service.internal_port, service.ports[0].port))
+            .collect();
+        assert_eq!(
+            vec![
+                (HEALTHCHECK_INTERNAL_PORT, HEALTHCHECK_EXTERNAL_PORT),
+                (9090, 443),
+                (9000, 8000)
+            ],
+            exposed
+        );
+    }
+
+    #[test]
+    fn wait_until_started_should_fail_on_terminal_state() {
+        let fly = FakeFly::with_app(APP);
+        fly.create_machines_in(MachineState::Stopped, HostStatus::Ok);
+        let err = setup_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        let AppInitModifyError::TempVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::MachineNotStarted, step_error.kind);
+        assert_eq!(
+            Some(MachineState::Stopped),
+            step_error.machine.map(|machine| machine.state)
+        );
+        // Failed without waiting.
+        assert_eq!(0, fly.state().now);
+
+        let fly = prepared("");
+        fly.create_machines_in(MachineState::Started, HostStatus::Unreachable);
+        let err = minio_start(&fly, APP, &options()).unwrap_err();
+        let AppInitModifyError::MinioVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::MachineNotStarted, step_error.kind);
+    }
+
+    #[test]
+    fn wait_until_started_should_observe_deadline() {
+        let fly = prepared("");
+        minio_start(&fly, APP, &options()).unwrap();
+        fly.create_machines_in(MachineState::Starting, HostStatus::Ok);
+        let start = fly.state().now;
+        let err = start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap_err();
+        let AppInitModifyError::FinalVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(ErrorKind::Timeout, step_error.kind);
+        let machine = step_error.machine.unwrap();
+        assert_eq!(MachineState::Starting, machine.state);
+        assert_eq!(Some(machine.id), step_error.machine_id);
+        assert!(fly.state().now - start > MACHINE_START_DEADLINE.as_secs());
+    }
+
+    #[test]
+    fn check_health_should_observe_deadline() {
+        let fly = FakeFly::with_app(APP);
+        fly.set_healthy_since(25);
+        check_health(&fly, APP, 60).unwrap();
+        assert_eq!(30, fly.state().now);
+
+        let fly = FakeFly::with_app(APP);
+        let err = check_health(&fly, APP, 30).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::HealthCheckFailed));
+        assert_eq!(40, fly.state().now);
+
+        let fly = FakeFly::default();
+        let err = check_health(&fly, APP, 30).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::AppDeleted));
+    }
+
+    #[test]
+    fn cleanup_should_undo_recorded_steps_unless_not_required() {
+        let fly = FakeFly::default();
+        let res = cleanup(
+            &fly,
+            APP,
+            Saga::default(),
+            AppInitModifyError::AppNameConflict,
+            false,
+        );
+        assert!(matches!(res, AppInitError::CleanupNotRequired));
+
+        let mut saga = Saga::default();
+        let err = saga
+            .step(&fly, APP, || {
+                app_create(&fly, "org", APP)?;
+                allocate_ip(&fly, APP)?;
+                setup_volume(&fly, APP, "", &[], &options())?;
+                create_volume(&fly, APP, MINIO_VOLUME_NAME, &options())?;
+                minio_start(&fly, APP, &options())?;
+                Err::<(), _>(AppInitModifyError::HealthCheckFailed)
+            })
+            .unwrap_err();
+        fly.fail("ips::release", "503 Service Unavailable");
+        let res = cleanup(&fly, APP, saga, err, false);
+        let AppInitError::CleanupFailed(AppCleanup {
+            modify_error: AppInitModifyError::HealthCheckFailed,
+            compensations,
+        }) = res
+        else {
+            panic!("unexpected result {res:?}");
+        };
+        // MinIO VM, both volumes, IP, app
+        assert_eq!(5, compensations.len());
+        assert_eq!(ResourceKind::Machine, compensations[0].kind);
+        assert!(compensations[3].error.is_some());
+        assert!(fly.state().apps.is_empty());
+    }
+
+    #[test]
+    fn update_config_should_keep_previous_config_on_failure() {
+        let fly = deployed("old");
+        let volume_id = volume_id(&fly);
+        fly.fail_exec("server verify", 1, "invalid configuration");
+        let err = update_config(&fly, APP, "new", &[]).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::VerifyError(_)));
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+    }
+
+    #[test]
+    fn update_config_and_rollback() {
+        let fly = deployed("");
+        let volume_id = volume_id(&fly);
+        let extra_file = |path: &str, contents: &str| ExtraFile {
+            path: path.to_string(),
+            contents: contents.to_string(),
+        };
+        update_config(&fly, APP, "old", &[extra_file("/volume/a.txt", "old")]).unwrap();
+        let extra_files = [
+            extra_file("/volume/a.txt", "new"),
+            extra_file("/volume/b.txt", "added"),
+        ];
+        update_config(&fly, APP, "new", &extra_files).unwrap();
+        assert_eq!(
+            Some("new".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+        assert_eq!(
+            Some("new".to_string()),
+            fly.file(&volume_id, "/volume/a.txt")
+        );
+        rollback_config(&fly, APP, &extra_files).unwrap();
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, "/volume/a.txt")
+        );
+        assert_eq!(None, fly.file(&volume_id, "/volume/b.txt"));
+    }
+
+    #[test]
+    fn failed_update_should_restore_extra_files() {
+        let fly = deployed("");
+        let volume_id = volume_id(&fly);
+        let extra_file = |path: &str, contents: &str| ExtraFile {
+            path: path.to_string(),
+            contents: contents.to_string(),
+        };
+        update_config(&fly, APP, "old", &[extra_file("/volume/a.txt", "old")]).unwrap();
+        // Writing the second file fails after the first one was overwritten.
+        fly.fail_exec("/volume/b.txt.", 1, "No space left on device");
+        let extra_files = [
+            extra_file("/volume/a.txt", "new"),
+            extra_file("/volume/b.txt", "added"),
+        ];
+        let err = update_config(&fly, APP, "new", &extra_files).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::VolumeWriteError(_)));
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, "/volume/a.txt")
+        );
+        assert_eq!(None, fly.file(&volume_id, "/volume/b.txt"));
+    }
+
+    #[test]
+    fn update_should_apply_extra_files_and_http_servers() {
+        let fly = deployed("old");
+        let volume_id = volume_id(&fly);
+        let extra_files = [ExtraFile {
+            path: "/volume/prompts/summary.txt".to_string(),
+            contents: "Summarize".to_string(),
+        }];
+        update_config(&fly, APP, "new", &extra_files).unwrap();
+        assert_eq!(
+            Some("Summarize".to_string()),
+            fly.file(&volume_id, "/volume/prompts/summary.txt")
+        );
+
+        let settings = FinalVmSettings {
+            machine_env: vec![("API_URL".to_string(), "https://example.com".to_string())],
+            http_servers: vec![HttpServer {
+                name: WEBHOOK_SERVER_NAME.to_string(),
+                internal_port: 9000,
+                external_port: Some(8000),
+                exposure: HttpExposure::PublicHttp,
+            }],
+        };
+        let previous_machine_config = apply_final_vm_settings(&fly, APP, &settings).unwrap();
+        let final_vm = fly
+            .app(APP)
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .cloned()
+            .unwrap();
+        let exposed: Vec<_> = final_vm
+            .config
+            .services
+            .iter()
+            .flatten()
+            .map(|service| (service.internal_port, service.ports[0].port))
+            .collect();
+        assert_eq!(
+            vec![
+                (HEALTHCHECK_INTERNAL_PORT, HEALTHCHECK_EXTERNAL_PORT),
+                (9000, 8000)
+            ],
+            exposed
+        );
+        let env = final_vm.config.env.clone().unwrap();
+        assert!(env.contains(&("API_URL".to_string(), "https://example.com".to_string())));
+        // The volume stays attached to the same VM.
+        assert_eq!(
+            Some(final_vm.id.clone()),
+            fly.app(APP).volumes[0].attached_machine_id
+        );
+
+        update_final_vm(&fly, APP, |machine_config| {
+            *machine_config = previous_machine_config;
+        })
+        .unwrap();
+        let final_vm = fly
+            .app(APP)
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .cloned()
+            .unwrap();
+        // Deployed without any public HTTP server.
+        assert_eq!(
+            vec![(HEALTHCHECK_INTERNAL_PORT, HEALTHCHECK_EXTERNAL_PORT)],
+            final_vm
+                .config
+                .services
+                .iter()
+                .flatten()
+                .map(|service| (service.internal_port, service.ports[0].port))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn restore_volume_should_delete_new_volume_on_failure() {
+        let fly = deployed("");
+        fly.fail_exec("litestream restore", 1, "no snapshots available");
+        let err = restore_volume(&fly, APP, "", &[], &options()).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::LitestreamRestoreError(_)));
+        let app = fly.app(APP);
+        assert_eq!(2, app.volumes.len());
+        assert!(
+            app.machines
+                .iter()
+                .all(|machine| machine.name != VM_NAME_TEMP)
+        );
+    }
+
+    #[test]
+    fn restore_volume_and_swap() {
+        let fly = deployed("");
+        let old_volume_id = volume_id(&fly);
+        let new_volume_id = restore_volume(&fly, APP, "", &[], &options()).unwrap();
+        swap_volume(&fly, APP, &new_volume_id).unwrap();
+        let app = fly.app(APP);
+        let final_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .unwrap();
+        let attached_to = |volume_id: &str| {
+            app.volumes
+                .iter()
+                .find(|volume| volume.id == volume_id)
+                .unwrap()
+                .attached_machine_id
+                .clone()
+        };
+        assert_eq!(Some(final_vm.id.clone()), attached_to(&new_volume_id));
+        assert_eq!(None, attached_to(&old_volume_id));
+    }
+
+    /// Switch to the restored volume using the native steps.
+    fn switch(fly: &FakeFly, restored_volume_id: &str) -> Result<(), RestoreError> {
+        switch_to_restored_volume(
+            fly,
+            APP,
+            restored_volume_id,
+            |volume_id| swap_volume(fly, APP, volume_id),
+            || check_health(fly, APP, 10),
+        )
+    }
+
+    #[test]
+    fn restored_volume_should_replace_superseded_one() {
+        let fly = deployed("");
+        let old_volume_id = volume_id(&fly);
+        let new_volume_id = restore_volume(&fly, APP, "", &[], &options()).unwrap();
+        let db_volumes = |fly: &FakeFly| {
+            fly.app(APP)
+                .volumes
+                .iter()
+                .filter(|volume| volume.name == VOLUME_NAME)
+                .map(|volume| volume.id.clone())
+                .collect::<Vec<_>>()
+        };
+        // Both volumes coexist until the final VM is healthy.
+        assert_eq!(vec![old_volume_id, new_volume_id.clone()], db_volumes(&fly));
+        fly.set_healthy_since(0);
+        switch(&fly, &new_volume_id).unwrap();
+        assert_eq!(vec![new_volume_id.clone()], db_volumes(&fly));
+
+        // A recreated final VM mounts the restored volume.
+        let final_vm_id = get_final_vm_id(&fly, APP).unwrap();
+        fly.machines_delete(APP, &final_vm_id, true).unwrap();
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert!(
+            reports
+                .iter()
+                .all(|report| matches!(report.correction, Some(Ok(())))),
+            "{reports:?}"
+        );
+        assert_eq!(new_volume_id, final_vm_volume_id(&fly, APP).unwrap());
+    }
+
+    #[test]
+    fn unhealthy_restored_volume_should_be_switched_back_and_deleted() {
+        let fly = deployed("");
+        let old_volume_id = volume_id(&fly);
+        let new_volume_id = restore_volume(&fly, APP, "", &[], &options()).unwrap();
+        let err = switch(&fly, &new_volume_id).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                RestoreError::SwapFailed(AppInitModifyError::HealthCheckFailed)
+            ),
+            "unexpected error {err:?}"
+        );
+        assert_eq!(old_volume_id, final_vm_volume_id(&fly, APP).unwrap());
+        assert_eq!(
+            1,
+            fly.app(APP)
+                .volumes
+                .iter()
+                .filter(|volume| volume.name == VOLUME_NAME)
+                .count()
+        );
+    }
+
+    #[test]
+    fn failed_switch_back_should_report_both_volumes() {
+        let fly = deployed("");
+        let old_volume_id = volume_id(&fly);
+        let new_volume_id = restore_volume(&fly, APP, "", &[], &options()).unwrap();
+        let err = switch_to_restored_volume(
+            &fly,
+            APP,
+            &new_volume_id,
+            |volume_id| {
+                if volume_id == new_volume_id {
+                    swap_volume(&fly, APP, volume_id)
+                } else {
+                    Err(AppInitModifyError::AppDeleted)
+                }
+            },
+            || Err(AppInitModifyError::HealthCheckFailed),
+        )
+        .unwrap_err();
+        let RestoreError::RollbackFailed(rollback_failed) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert!(matches!(
+            rollback_failed.swap_error,
+            AppInitModifyError::HealthCheckFailed
+        ));
+        assert_eq!(new_volume_id, rollback_failed.restored_volume_id);
+        assert_eq!(old_volume_id, rollback_failed.previous_volume_id);
+        assert_eq!(new_volume_id, final_vm_volume_id(&fly, APP).unwrap());
+        assert_eq!(
+            2,
+            fly.app(APP)
+                .volumes
+                .iter()
+                .filter(|volume| volume.name == VOLUME_NAME)
+                .count()
+        );
+    }
+
+    #[test]
+    fn failed_swap_should_keep_final_vm() {
+        let fly = deployed("");
+        let old_volume_id = volume_id(&fly);
+        let new_volume_id = restore_volume(&fly, APP, "", &[], &options()).unwrap();
+        let final_vm_id = get_final_vm_id(&fly, APP).unwrap();
+        fly.fail("machines::update", "500 Internal Server Error");
+        let err = swap_volume(&fly, APP, &new_volume_id).unwrap_err();
+        let AppInitModifyError::FinalVmError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("update-final-vm", step_error.step);
+        assert_eq!(final_vm_id, get_final_vm_id(&fly, APP).unwrap());
+        let app = fly.app(APP);
+        let old_volume = app
+            .volumes
+            .iter()
+            .find(|volume| volume.id == old_volume_id)
+            .unwrap();
+        assert_eq!(Some(final_vm_id), old_volume.attached_machine_id);
+    }
 }
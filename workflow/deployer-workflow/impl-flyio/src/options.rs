@@ -0,0 +1,155 @@
+use crate::generated::obelisk_flyio::{
+    activity_fly_http::{
+        machines::{CpuKind, GuestConfig},
+        regions::Region,
+    },
+    workflow::types::{
+        AppInitModifyError, DeploymentOptions, SyncSecretsError, SyncSecretsOptions, VmOptions,
+        WatchOptions,
+    },
+};
+use crate::{LITESTREAM_SHA256, LITESTREAM_VERSION};
+
+const DEFAULT_REGION: Region = Region::Ams;
+const DEFAULT_OBELISK_IMAGE: &str = "getobelisk/obelisk:0.25.3-ubuntu";
+const DEFAULT_MINIO_IMAGE: &str = "minio/minio:RELEASE.2025-09-07T16-13-09Z-cpuv1";
+const DEFAULT_VOLUME_SIZE_GB: u32 = 1;
+const DEFAULT_WATCH_INTERVAL_SECS: u32 = 60;
+const DEFAULT_WATCH_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_WATCH_MAX_RESTARTS: u32 = 2;
+const DEFAULT_WATCH_MAX_REPLACEMENTS: u32 = 1;
+const DEFAULT_WATCH_RECOVERY_PROBES: u32 = 10;
+const DEFAULT_WATCH_MAX_PROBES: u32 = 1440;
+const DEFAULT_SYNC_MAX_CHECKS: u32 = 1440;
+const DEFAULT_OBELISK_VM: VmOptions = VmOptions {
+    cpu_kind: Some(CpuKind::Shared),
+    cpus: Some(1),
+    memory_mb: Some(256),
+    swap_size_mb: Some(256),
+};
+const DEFAULT_MINIO_VM: VmOptions = VmOptions {
+    cpu_kind: Some(CpuKind::Shared),
+    cpus: Some(1),
+    memory_mb: Some(256),
+    swap_size_mb: None,
+};
+
+impl VmOptions {
+    fn or(self, defaults: VmOptions) -> VmOptions {
+        VmOptions {
+            cpu_kind: self.cpu_kind.or(defaults.cpu_kind),
+            cpus: self.cpus.or(defaults.cpus),
+            memory_mb: self.memory_mb.or(defaults.memory_mb),
+            swap_size_mb: self.swap_size_mb.or(defaults.swap_size_mb),
+        }
+    }
+
+    pub(crate) fn guest_config(&self) -> GuestConfig {
+        GuestConfig {
+            cpu_kind: self.cpu_kind,
+            cpus: self.cpus,
+            memory_mb: self.memory_mb,
+            kernel_args: None,
+        }
+    }
+}
+
+impl DeploymentOptions {
+    pub(crate) fn region(&self) -> Region {
+        self.region.unwrap_or(DEFAULT_REGION)
+    }
+
+    pub(crate) fn obelisk_image(&self) -> &str {
+        self.obelisk_image
+            .as_deref()
+            .unwrap_or(DEFAULT_OBELISK_IMAGE)
+    }
+
+    pub(crate) fn minio_image(&self) -> &str {
+        self.minio_image.as_deref().unwrap_or(DEFAULT_MINIO_IMAGE)
+    }
+
+    /// Settings of the final VM and the temporary VM.
+    pub(crate) fn obelisk_vm(&self) -> VmOptions {
+        self.obelisk_vm
+            .map_or(DEFAULT_OBELISK_VM, |vm| vm.or(DEFAULT_OBELISK_VM))
+    }
+
+    pub(crate) fn minio_vm(&self) -> VmOptions {
+        self.minio_vm
+            .map_or(DEFAULT_MINIO_VM, |vm| vm.or(DEFAULT_MINIO_VM))
+    }
+
+    pub(crate) fn volume_size_gb(&self) -> u32 {
+        self.volume_size_gb.unwrap_or(DEFAULT_VOLUME_SIZE_GB)
+    }
+
+    /// Digest of the Litestream tarball, `LITESTREAM_SHA256` unless overridden.
+    pub(crate) fn litestream_sha256(&self) -> Result<&str, AppInitModifyError> {
+        let digest = self
+            .litestream_sha256
+            .as_deref()
+            .unwrap_or(LITESTREAM_SHA256);
+        if digest.is_empty() {
+            Err(AppInitModifyError::ConfigInvalid(format!(
+                "no digest is pinned for Litestream {LITESTREAM_VERSION}, `litestream-sha256` is required"
+            )))
+        } else if digest.len() == 64 && digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            Ok(digest)
+        } else {
+            Err(AppInitModifyError::ConfigInvalid(
+                "`litestream-sha256` must be 64 hexadecimal characters".to_string(),
+            ))
+        }
+    }
+}
+
+impl WatchOptions {
+    pub(crate) fn interval_secs(&self) -> u32 {
+        self.interval_secs.unwrap_or(DEFAULT_WATCH_INTERVAL_SECS)
+    }
+
+    pub(crate) fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+            .unwrap_or(DEFAULT_WATCH_FAILURE_THRESHOLD)
+            .max(1)
+    }
+
+    pub(crate) fn max_restarts(&self) -> u32 {
+        self.max_restarts.unwrap_or(DEFAULT_WATCH_MAX_RESTARTS)
+    }
+
+    pub(crate) fn max_replacements(&self) -> u32 {
+        self.max_replacements
+            .unwrap_or(DEFAULT_WATCH_MAX_REPLACEMENTS)
+    }
+
+    pub(crate) fn recovery_probes(&self) -> u32 {
+        self.recovery_probes
+            .unwrap_or(DEFAULT_WATCH_RECOVERY_PROBES)
+            .max(1)
+    }
+
+    pub(crate) fn max_probes(&self) -> u32 {
+        self.max_probes.unwrap_or(DEFAULT_WATCH_MAX_PROBES)
+    }
+}
+
+impl SyncSecretsOptions {
+    /// Seconds between comparisons, `None` if the digests are compared once.
+    pub(crate) fn interval_secs(&self) -> Result<Option<u32>, SyncSecretsError> {
+        match self.interval_secs {
+            Some(0) => Err(SyncSecretsError::InvalidOptions(
+                "`interval-secs` must be positive".to_string(),
+            )),
+            interval_secs => Ok(interval_secs),
+        }
+    }
+
+    pub(crate) fn max_checks(&self) -> u32 {
+        match self.interval_secs {
+            Some(_) => self.max_checks.unwrap_or(DEFAULT_SYNC_MAX_CHECKS),
+            None => 1,
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use crate::activities::Activities;
+use crate::generated::{
+    obelisk::types::time::{Datetime, ScheduleAt},
+    obelisk_flyio::{
+        activity_fly_http::{
+            apps::App,
+            ips::{IpDetail, IpRequest},
+            machines::{ExecResponse, HostStatus, Machine, MachineConfig, MachineState},
+            regions::Region,
+            secrets::Secret,
+            volumes::{Volume, VolumeCreateRequest},
+        },
+        workflow::{
+            types::{
+                AppInitModifyError, DeploymentPlan, ExtraFile, PlannedApp, PlannedExec,
+                PlannedFile, PlannedMachine, PlannedOperation,
+            },
+            workflow::DeploymentOptions,
+        },
+    },
+    testing::http::http_get,
+};
+use crate::transfer::{ChunkWrite, sha256_hex};
+use crate::{FinalVmSettings, minio_configure, minio_start, prepare, start_final_vm};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const GENERATED_PLACEHOLDER: &str = "<generated>";
+
+/// Records the mutating activities instead of executing them.
+///
+/// Reads are answered from the recorded state, as if every operation succeeded.
+/// Machines and volumes get their names as IDs.
+/// Transferred files are reassembled and recorded once they are moved into place.
+#[derive(Default)]
+struct Planner {
+    state: RefCell<PlannerState>,
+}
+
+#[derive(Default)]
+struct PlannerState {
+    operations: Vec<PlannedOperation>,
+    app_exists: bool,
+    ips: Vec<IpDetail>,
+    machines: Vec<Machine>,
+    /// Files being transferred, keyed by machine ID and path.
+    files: HashMap<(String, String), Vec<u8>>,
+}
+
+impl Planner {
+    fn record(&self, operation: PlannedOperation) {
+        self.state.borrow_mut().operations.push(operation);
+    }
+}
+
+fn not_planned<T>(activity: &str) -> Result<T, String> {
+    Err(format!("{activity} is not expected while planning"))
+}
+
+impl Activities for Planner {
+    fn apps_get(&self, app_name: &str) -> Result<Option<App>, String> {
+        Ok(self.state.borrow().app_exists.then(|| App {
+            name: app_name.to_string(),
+            id: app_name.to_string(),
+        }))
+    }
+
+    fn apps_put(&self, org_slug: &str, app_name: &str) -> Result<App, String> {
+        self.record(PlannedOperation::CreateApp(PlannedApp {
+            org_slug: org_slug.to_string(),
+            app_name: app_name.to_string(),
+        }));
+        self.state.borrow_mut().app_exists = true;
+        Ok(App {
+            name: app_name.to_string(),
+            id: app_name.to_string(),
+        })
+    }
+
+    fn apps_delete(&self, _app_name: &str, _force: bool) -> Result<(), String> {
+        not_planned("apps::delete")
+    }
+
+    fn ips_allocate_unsafe(&self, _app_name: &str, request: IpRequest) -> Result<String, String> {
+        self.record(PlannedOperation::AllocateIp(request));
+        let mut state = self.state.borrow_mut();
+        state.ips.push(IpDetail {
+            ip: GENERATED_PLACEHOLDER.to_string(),
+            ip_variant: request.config,
+        });
+        Ok(GENERATED_PLACEHOLDER.to_string())
+    }
+
+    fn ips_list(&self, _app_name: &str) -> Result<Vec<IpDetail>, String> {
+        Ok(self.state.borrow().ips.clone())
+    }
+
+    fn ips_release(&self, _app_name: &str, _ip: &str) -> Result<(), String> {
+        not_planned("ips::release")
+    }
+
+    fn machines_list(&self, _app_name: &str) -> Result<Vec<Machine>, String> {
+        Ok(self.state.borrow().machines.clone())
+    }
+
+    fn machines_get(&self, _app_name: &str, machine_id: &str) -> Result<Option<Machine>, String> {
+        Ok(self
+            .state
+            .borrow()
+            .machines
+            .iter()
+            .find(|machine| machine.id == machine_id)
+            .cloned())
+    }
+
+    fn machines_create(
+        &self,
+        _app_name: &str,
+        machine_name: &str,
+        machine_config: &MachineConfig,
+        region: Option<Region>,
+    ) -> Result<String, String> {
+        self.record(PlannedOperation::CreateMachine(PlannedMachine {
+            name: machine_name.to_string(),
+            config: machine_config.clone(),
+            region,
+        }));
+        self.state.borrow_mut().machines.push(Machine {
+            config: machine_config.clone(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            id: machine_name.to_string(),
+            instance_id: machine_name.to_string(),
+            name: machine_name.to_string(),
+            state: MachineState::Started,
+            region: region.unwrap_or(Region::Ams),
+            host_status: HostStatus::Ok,
+        });
+        Ok(machine_name.to_string())
+    }
+
+    fn machines_update(
+        &self,
+        _app_name: &str,
+        _machine_id: &str,
+        _machine_config: &MachineConfig,
+        _region: Option<Region>,
+    ) -> Result<(), String> {
+        not_planned("machines::update")
+    }
+
+    fn machines_stop(&self, _app_name: &str, machine_id: &str) -> Result<(), String> {
+        self.record(PlannedOperation::StopMachine(machine_id.to_string()));
+        Ok(())
+    }
+
+    fn machines_restart(&self, _app_name: &str, _machine_id: &str) -> Result<(), String> {
+        not_planned("machines::restart")
+    }
+
+    fn machines_delete(
+        &self,
+        _app_name: &str,
+        machine_id: &str,
+        _force: bool,
+    ) -> Result<(), String> {
+        self.record(PlannedOperation::DeleteMachine(machine_id.to_string()));
+        self.state
+            .borrow_mut()
+            .machines
+            .retain(|machine| machine.id != machine_id);
+        Ok(())
+    }
+
+    fn machines_exec(
+        &self,
+        _app_name: &str,
+        machine_id: &str,
+        command: &[String],
+    ) -> Result<ExecResponse, String> {
+        let mut exec_response = ExecResponse {
+            exit_code: Some(0),
+            exit_signal: None,
+            stderr: Some(String::new()),
+            stdout: Some(String::new()),
+        };
+        let mut state = self.state.borrow_mut();
+        let key = |path: &str| (machine_id.to_string(), path.to_string());
+        if let Some(chunk) = ChunkWrite::parse(command) {
+            chunk.apply(state.files.entry(key(chunk.path)).or_default())?;
+            return Ok(exec_response);
+        }
+        match command {
+            [sha256sum, path] if sha256sum == "sha256sum" => {
+                let digest = sha256_hex(state.files.get(&key(path)).map_or(&[][..], Vec::as_slice));
+                exec_response.stdout = Some(format!("{digest}  {path}\n"));
+            }
+            [mv, from, to] if mv == "mv" && state.files.contains_key(&key(from)) => {
+                let contents = state.files.remove(&key(from)).unwrap_or_default();
+                state
+                    .operations
+                    .push(PlannedOperation::WriteFile(PlannedFile {
+                        machine_name: machine_id.to_string(),
+                        path: to.clone(),
+                        contents: String::from_utf8_lossy(&contents).into_owned(),
+                    }));
+            }
+            _ => state.operations.push(PlannedOperation::Exec(PlannedExec {
+                machine_name: machine_id.to_string(),
+                command: command.to_vec(),
+            })),
+        }
+        Ok(exec_response)
+    }
+
+    fn secrets_list(&self, _app_name: &str) -> Result<Vec<Secret>, String> {
+        not_planned("secrets::list")
+    }
+
+    fn secrets_delete(&self, _app_name: &str, _secret_name: &str) -> Result<(), String> {
+        not_planned("secrets::delete")
+    }
+
+    fn volumes_list(&self, _app_name: &str) -> Result<Vec<Volume>, String> {
+        not_planned("volumes::list")
+    }
+
+    fn volumes_create(
+        &self,
+        _app_name: &str,
+        request: &VolumeCreateRequest,
+    ) -> Result<Volume, String> {
+        self.record(PlannedOperation::CreateVolume(request.clone()));
+        Ok(Volume {
+            id: request.name.clone(),
+            name: request.name.clone(),
+            state: "created".to_string(),
+            region: request.region,
+            size_gb: request.size_gb,
+            encrypted: true,
+            attached_machine_id: None,
+            host_status: "ok".to_string(),
+            created_at: String::new(),
+            blocks: 0,
+            block_size: 0,
+            blocks_free: 0,
+            blocks_avail: 0,
+            bytes_used: 0,
+            bytes_total: 0,
+        })
+    }
+
+    fn volumes_delete(&self, _app_name: &str, _volume_id: &str) -> Result<(), String> {
+        not_planned("volumes::delete")
+    }
+
+    fn http_get_resp(&self, _url: &str) -> Result<http_get::Response, String> {
+        not_planned("http::get")
+    }
+
+    fn sleep(&self, _schedule_at: ScheduleAt) -> Datetime {
+        Datetime {
+            seconds: 0,
+            nanoseconds: 0,
+        }
+    }
+
+    fn sleep_named(&self, _name: &str, schedule_at: ScheduleAt) -> Datetime {
+        self.sleep(schedule_at)
+    }
+
+    fn random_string(&self, _min_length: u16, _max_length_exclusive: u16) -> String {
+        GENERATED_PLACEHOLDER.to_string()
+    }
+}
+
+/// Run the steps of `app_init` that touch Fly.io against the [`Planner`].
+pub(crate) fn plan(
+    org_slug: &str,
+    app_name: &str,
+    obelisk_toml: String,
+    extra_files: &[ExtraFile],
+    required_secrets: Vec<String>,
+    final_vm_settings: &FinalVmSettings,
+    options: &DeploymentOptions,
+) -> Result<DeploymentPlan, AppInitModifyError> {
+    let planner = Planner::default();
+    prepare(
+        &planner,
+        org_slug,
+        app_name,
+        &obelisk_toml,
+        extra_files,
+        options,
+    )?;
+    let minio_vm_id = minio_start(&planner, app_name, options)?;
+    minio_configure(&planner, app_name, &minio_vm_id)?;
+    start_final_vm(&planner, app_name, options, final_vm_settings)?;
+    Ok(DeploymentPlan {
+        operations: planner.state.into_inner().operations,
+        required_secrets,
+        obelisk_toml,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::plan;
+    use crate::generated::obelisk_flyio::workflow::{
+        types::{ExtraFile, PlannedOperation},
+        workflow::DeploymentOptions,
+    };
+    use crate::{
+        FinalVmSettings, LITESTREAM_YML_PATH, MINIO_VM_NAME, MINIO_VOLUME_NAME, OBELISK_TOML_PATH,
+        VM_NAME_FINAL, VM_NAME_TEMP, VOLUME_NAME, fake,
+    };
+
+    fn options() -> DeploymentOptions {
+        DeploymentOptions {
+            volume_size_gb: Some(5),
+            ..fake::options()
+        }
+    }
+
+    fn summary(operation: &PlannedOperation) -> String {
+        match operation {
+            PlannedOperation::CreateApp(app) => format!("create-app {}", app.app_name),
+            PlannedOperation::AllocateIp(_) => "allocate-ip".to_string(),
+            PlannedOperation::CreateVolume(request) => {
+                format!("create-volume {} {}GB", request.name, request.size_gb)
+            }
+            PlannedOperation::CreateMachine(machine) => format!("create-machine {}", machine.name),
+            PlannedOperation::Exec(exec) => format!("exec {}", exec.machine_name),
+            PlannedOperation::WriteFile(file) => {
+                format!("write-file {} {}", file.machine_name, file.path)
+            }
+            PlannedOperation::StopMachine(name) => format!("stop-machine {name}"),
+            PlannedOperation::DeleteMachine(name) => format!("delete-machine {name}"),
+        }
+    }
+
+    #[test]
+    fn plan_should_list_operations_of_app_init() {
+        let plan = plan(
+            "org",
+            "test-app",
+            "toml".to_string(),
+            &[ExtraFile {
+                path: "/volume/ca/bundle.pem".to_string(),
+                contents: "pem".to_string(),
+            }],
+            vec!["SECRET".to_string()],
+            &FinalVmSettings::default(),
+            &options(),
+        )
+        .unwrap();
+        let creating: Vec<_> = plan
+            .operations
+            .iter()
+            .map(summary)
+            .filter(|summary| !summary.starts_with("exec"))
+            .collect();
+        let temp_vm_files = [
+            OBELISK_TOML_PATH,
+            "/volume/ca/bundle.pem",
+            LITESTREAM_YML_PATH,
+        ]
+        .map(|path| format!("write-file {VM_NAME_TEMP} {path}"));
+        assert_eq!(
+            vec![
+                "create-app test-app".to_string(),
+                "allocate-ip".to_string(),
+                format!("create-volume {VOLUME_NAME} 5GB"),
+                format!("create-machine {VM_NAME_TEMP}"),
+                temp_vm_files[0].clone(),
+                temp_vm_files[1].clone(),
+                temp_vm_files[2].clone(),
+                format!("stop-machine {VM_NAME_TEMP}"),
+                format!("delete-machine {VM_NAME_TEMP}"),
+                format!("create-volume {MINIO_VOLUME_NAME} 5GB"),
+                format!("create-machine {MINIO_VM_NAME}"),
+                format!("create-machine {VM_NAME_FINAL}"),
+            ],
+            creating
+        );
+        assert_eq!(vec!["SECRET".to_string()], plan.required_secrets);
+        assert_eq!("toml", plan.obelisk_toml);
+        assert!(plan.operations.iter().any(|operation| matches!(
+            operation,
+            PlannedOperation::WriteFile(file) if file.path == OBELISK_TOML_PATH && file.contents == "toml"
+        )));
+    }
+
+    #[test]
+    fn plan_should_not_contain_generated_credentials() {
+        let plan = plan(
+            "org",
+            "test-app",
+            String::new(),
+            &[],
+            Vec::new(),
+            &FinalVmSettings {
+                machine_env: vec![("API_URL".to_string(), "https://example.com".to_string())],
+                ..FinalVmSettings::default()
+            },
+            &options(),
+        )
+        .unwrap();
+        let final_vm = plan
+            .operations
+            .iter()
+            .find_map(|operation| match operation {
+                PlannedOperation::CreateMachine(machine) if machine.name == VM_NAME_FINAL => {
+                    Some(machine)
+                }
+                _ => None,
+            })
+            .unwrap();
+        let env = final_vm.config.env.clone().unwrap();
+        assert!(
+            env.iter()
+                .any(|(key, value)| key == "LITESTREAM_SECRET_ACCESS_KEY" && value == "<generated>")
+        );
+        assert!(
+            env.iter()
+                .any(|(key, value)| key == "API_URL" && value == "https://example.com")
+        );
+        assert!(
+            final_vm
+                .config
+                .services
+                .as_ref()
+                .is_some_and(|s| !s.is_empty())
+        );
+    }
+}
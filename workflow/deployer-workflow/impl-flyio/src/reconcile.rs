@@ -0,0 +1,440 @@
+use crate::activities::Activities;
+use crate::error::fly_error;
+use crate::generated::obelisk_flyio::{
+    activity_fly_http::{
+        ips::IpVariant,
+        machines::{Machine, MachineConfig, Mount},
+        volumes::Volume,
+    },
+    workflow::{
+        types::{AppInitModifyError, Drift, DriftReport, MachineDrift, ReconcileError},
+        workflow::DeploymentOptions,
+    },
+};
+use crate::{
+    FinalVmSettings, MINIO_VM_NAME, MINIO_VOLUME_NAME, VM_NAME_FINAL, VM_NAME_TEMP, VOLUME_NAME,
+    allocate_ip, delete_temp_vm, final_machine_config, final_vm_env, find_machine_id,
+    minio_configure, minio_machine_config, minio_start, start_final_vm, update_final_vm,
+};
+use hashbrown::HashSet;
+use std::fmt::Debug;
+
+/// How a drift can be corrected.
+enum Correction {
+    AllocateIp,
+    ReleaseIp(String),
+    DeleteTempVm(String),
+    StartMinio,
+    StartFinalVm,
+}
+
+/// Compare the app with the desired deployment, correcting the drift if requested.
+pub(crate) fn reconcile(
+    act: &impl Activities,
+    app_name: &str,
+    required_secrets: &HashSet<String>,
+    final_vm_settings: &FinalVmSettings,
+    options: &DeploymentOptions,
+    correct: bool,
+) -> Result<Vec<DriftReport>, ReconcileError> {
+    if act
+        .apps_get(app_name)
+        .map_err(fly_error(ReconcileError::ListError, "get-app"))?
+        .is_none()
+    {
+        return Err(ReconcileError::AppNotFound);
+    }
+    let drifts = detect(act, app_name, required_secrets, final_vm_settings, options)?;
+    Ok(drifts
+        .into_iter()
+        .map(|(drift, correction)| DriftReport {
+            drift,
+            correction: correction
+                .filter(|_| correct)
+                .map(|correction| apply(act, app_name, options, final_vm_settings, correction)),
+        })
+        .collect())
+}
+
+/// Drift in the order it should be corrected: the MinIO VM must exist before the final VM.
+fn detect(
+    act: &impl Activities,
+    app_name: &str,
+    required_secrets: &HashSet<String>,
+    final_vm_settings: &FinalVmSettings,
+    options: &DeploymentOptions,
+) -> Result<Vec<(Drift, Option<Correction>)>, ReconcileError> {
+    let ips = act
+        .ips_list(app_name)
+        .map_err(fly_error(ReconcileError::ListError, "list-ips"))?;
+    let volumes = act
+        .volumes_list(app_name)
+        .map_err(fly_error(ReconcileError::ListError, "list-volumes"))?;
+    let machines = act
+        .machines_list(app_name)
+        .map_err(fly_error(ReconcileError::ListError, "list-machines"))?;
+    let secrets: HashSet<String> = act
+        .secrets_list(app_name)
+        .map_err(fly_error(ReconcileError::ListError, "list-secrets"))?
+        .into_iter()
+        .map(|secret| secret.name)
+        .collect();
+
+    let mut drifts = Vec::new();
+    let ipv6 = ips
+        .iter()
+        .position(|ip_detail| matches!(ip_detail.ip_variant, IpVariant::Ipv6(_)));
+    if ipv6.is_none() {
+        drifts.push((Drift::IpMissing, Some(Correction::AllocateIp)));
+    }
+    for (idx, ip_detail) in ips.into_iter().enumerate() {
+        if Some(idx) != ipv6 {
+            drifts.push((
+                Drift::IpExtra(ip_detail.ip.clone()),
+                Some(Correction::ReleaseIp(ip_detail.ip)),
+            ));
+        }
+    }
+    for volume_name in [VOLUME_NAME, MINIO_VOLUME_NAME] {
+        if !volumes.iter().any(|volume| volume.name == volume_name) {
+            drifts.push((Drift::VolumeMissing(volume_name.to_string()), None));
+        }
+    }
+    // The temporary VM might keep the volume attached, delete it before starting the final VM.
+    for machine in machines
+        .iter()
+        .filter(|machine| machine.name == VM_NAME_TEMP)
+    {
+        drifts.push((
+            Drift::TempVm(machine.id.clone()),
+            Some(Correction::DeleteTempVm(machine.id.clone())),
+        ));
+    }
+    // Environment is not compared, it contains the generated credentials.
+    for (name, desired, correction) in [
+        (
+            MINIO_VM_NAME,
+            minio_machine_config(options, Vec::new()),
+            Correction::StartMinio,
+        ),
+        (
+            VM_NAME_FINAL,
+            final_machine_config(options, Vec::new(), &final_vm_settings.http_servers),
+            Correction::StartFinalVm,
+        ),
+    ] {
+        match machines.iter().find(|machine| machine.name == name) {
+            Some(machine) => drifts.extend(
+                compare_machine(machine, &desired, &volumes)
+                    .into_iter()
+                    .map(|machine_drift| (Drift::MachineChanged(machine_drift), None)),
+            ),
+            None => drifts.push((Drift::MachineMissing(name.to_string()), Some(correction))),
+        }
+    }
+    let mut missing_secrets: Vec<_> = required_secrets.difference(&secrets).collect();
+    missing_secrets.sort();
+    drifts.extend(
+        missing_secrets
+            .into_iter()
+            .map(|secret| (Drift::SecretMissing(secret.clone()), None)),
+    );
+    Ok(drifts)
+}
+
+fn compare_machine(
+    machine: &Machine,
+    desired: &MachineConfig,
+    volumes: &[Volume],
+) -> Vec<MachineDrift> {
+    // Mounts refer to volumes by their IDs, the desired configuration by their names.
+    let mounts = machine.config.mounts.as_ref().map(|mounts| {
+        mounts
+            .iter()
+            .map(|mount| Mount {
+                volume: volumes
+                    .iter()
+                    .find(|volume| volume.id == mount.volume)
+                    .map_or_else(|| mount.volume.clone(), |volume| volume.name.clone()),
+                path: mount.path.clone(),
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut drifts = Vec::new();
+    let mut compare = |field: &str, expected: &dyn Debug, actual: &dyn Debug| {
+        let (expected, actual) = (format!("{expected:?}"), format!("{actual:?}"));
+        if expected != actual {
+            drifts.push(MachineDrift {
+                name: machine.name.clone(),
+                id: machine.id.clone(),
+                field: field.to_string(),
+                expected,
+                actual,
+            });
+        }
+    };
+    compare("image", &desired.image, &machine.config.image);
+    compare("guest", &desired.guest, &machine.config.guest);
+    compare("mounts", &desired.mounts, &mounts);
+    compare("services", &desired.services, &machine.config.services);
+    drifts
+}
+
+fn apply(
+    act: &impl Activities,
+    app_name: &str,
+    options: &DeploymentOptions,
+    final_vm_settings: &FinalVmSettings,
+    correction: Correction,
+) -> Result<(), AppInitModifyError> {
+    match correction {
+        Correction::AllocateIp => allocate_ip(act, app_name),
+        Correction::ReleaseIp(ip) => act
+            .ips_release(app_name, &ip)
+            .map_err(fly_error(AppInitModifyError::IpAllocateError, "release-ip")),
+        Correction::DeleteTempVm(machine_id) => delete_temp_vm(act, app_name, &machine_id),
+        Correction::StartMinio => {
+            let minio_vm_id = minio_start(act, app_name, options)?;
+            minio_configure(act, app_name, &minio_vm_id)?;
+            // Litestream of the running final VM still uses the previous MinIO VM.
+            let final_vm_id = find_machine_id(act, app_name, VM_NAME_FINAL)
+                .map_err(fly_error(AppInitModifyError::FinalVmError, "list-machines"))?;
+            if final_vm_id.is_some() {
+                let env = final_vm_env(act, app_name, &final_vm_settings.machine_env)
+                    .map_err(AppInitModifyError::FinalVmError)?;
+                update_final_vm(act, app_name, |machine_config| {
+                    machine_config.env = Some(env);
+                })?;
+            }
+            Ok(())
+        }
+        Correction::StartFinalVm => start_final_vm(act, app_name, options, final_vm_settings),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reconcile;
+    use crate::activities::Activities as _;
+    use crate::fake::{APP, FakeFly, deployed, options};
+    use crate::generated::obelisk_flyio::{
+        activity_fly_http::ips::{IpRequest, IpVariant, Ipv4Config},
+        workflow::{
+            types::{AppInitModifyError, Drift, ErrorKind, ReconcileError},
+            workflow::DeploymentOptions,
+        },
+    };
+    use crate::{FinalVmSettings, MINIO_VM_NAME, VM_NAME_FINAL, VM_NAME_TEMP, start_temp_vm};
+    use hashbrown::HashSet;
+
+    fn machine_id(fly: &FakeFly, name: &str) -> Option<String> {
+        fly.app(APP)
+            .machines
+            .iter()
+            .find(|machine| machine.name == name)
+            .map(|machine| machine.id.clone())
+    }
+
+    #[test]
+    fn reconcile_should_fail_without_app() {
+        let fly = FakeFly::default();
+        let err = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ReconcileError::AppNotFound));
+    }
+
+    #[test]
+    fn deployed_app_should_have_no_drift() {
+        let fly = deployed("");
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert!(reports.is_empty(), "{reports:?}");
+    }
+
+    #[test]
+    fn reconcile_should_report_changed_config_and_missing_secrets() {
+        let fly = deployed("");
+        let options = DeploymentOptions {
+            obelisk_image: Some("getobelisk/obelisk:latest".to_string()),
+            ..options()
+        };
+        let required_secrets = HashSet::from_iter(["SECRET".to_string()]);
+        let reports = reconcile(
+            &fly,
+            APP,
+            &required_secrets,
+            &FinalVmSettings::default(),
+            &options,
+            true,
+        )
+        .unwrap();
+        assert_eq!(2, reports.len(), "{reports:?}");
+        match &reports[0].drift {
+            Drift::MachineChanged(machine_drift) => {
+                assert_eq!(VM_NAME_FINAL, machine_drift.name);
+                assert_eq!("image", machine_drift.field);
+                assert!(machine_drift.expected.contains("latest"));
+            }
+            other => panic!("unexpected drift {other:?}"),
+        }
+        assert!(matches!(&reports[1].drift, Drift::SecretMissing(secret) if secret == "SECRET"));
+        assert!(reports.iter().all(|report| report.correction.is_none()));
+    }
+
+    #[test]
+    fn reconcile_should_only_report_unless_correcting() {
+        let fly = deployed("");
+        let final_vm_id = machine_id(&fly, VM_NAME_FINAL).unwrap();
+        fly.machines_delete(APP, &final_vm_id, true).unwrap();
+
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(1, reports.len());
+        assert!(matches!(&reports[0].drift, Drift::MachineMissing(name) if name == VM_NAME_FINAL));
+        assert!(reports[0].correction.is_none());
+        assert_eq!(None, machine_id(&fly, VM_NAME_FINAL));
+    }
+
+    #[test]
+    fn reconcile_should_correct_ips_temp_vm_and_missing_final_vm() {
+        let fly = deployed("");
+        let final_vm_id = machine_id(&fly, VM_NAME_FINAL).unwrap();
+        fly.machines_delete(APP, &final_vm_id, true).unwrap();
+        let volume_id = fly.app(APP).volumes[0].id.clone();
+        start_temp_vm(&fly, APP, &volume_id, &options(), None).unwrap();
+        fly.ips_allocate_unsafe(
+            APP,
+            IpRequest {
+                config: IpVariant::Ipv4(Ipv4Config {
+                    region: None,
+                    shared: true,
+                }),
+            },
+        )
+        .unwrap();
+
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(3, reports.len(), "{reports:?}");
+        assert!(matches!(reports[0].drift, Drift::IpExtra(_)));
+        assert!(matches!(reports[1].drift, Drift::TempVm(_)));
+        assert!(matches!(&reports[2].drift, Drift::MachineMissing(name) if name == VM_NAME_FINAL));
+        assert!(
+            reports
+                .iter()
+                .all(|report| matches!(report.correction, Some(Ok(()))))
+        );
+        assert_eq!(None, machine_id(&fly, VM_NAME_TEMP));
+        assert_eq!(1, fly.app(APP).ips.len());
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert!(reports.is_empty(), "{reports:?}");
+    }
+
+    #[test]
+    fn recreating_minio_should_update_final_vm() {
+        let fly = deployed("");
+        let minio_vm_id = machine_id(&fly, MINIO_VM_NAME).unwrap();
+        fly.machines_delete(APP, &minio_vm_id, true).unwrap();
+        let final_vm_id = machine_id(&fly, VM_NAME_FINAL).unwrap();
+
+        let machine_env = vec![("API_URL".to_string(), "https://example.com".to_string())];
+        let final_vm_settings = FinalVmSettings {
+            machine_env: machine_env.clone(),
+            ..FinalVmSettings::default()
+        };
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &final_vm_settings,
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(1, reports.len());
+        assert!(matches!(reports[0].correction, Some(Ok(()))));
+        let minio_vm_id = machine_id(&fly, MINIO_VM_NAME).unwrap();
+        let app = fly.app(APP);
+        let final_vm = app
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .unwrap();
+        // Updated in place.
+        assert_eq!(final_vm_id, final_vm.id);
+        assert!(
+            final_vm
+                .config
+                .env
+                .iter()
+                .flatten()
+                .any(|(key, value)| key == "LITESTREAM_ENDPOINT" && value.contains(&minio_vm_id))
+        );
+        assert!(
+            final_vm
+                .config
+                .env
+                .iter()
+                .flatten()
+                .any(|entry| entry == &machine_env[0])
+        );
+    }
+
+    #[test]
+    fn failed_correction_should_report_typed_error() {
+        let fly = deployed("");
+        let final_vm_id = machine_id(&fly, VM_NAME_FINAL).unwrap();
+        fly.machines_delete(APP, &final_vm_id, true).unwrap();
+        fly.fail("machines::create", "insufficient memory");
+        let reports = reconcile(
+            &fly,
+            APP,
+            &HashSet::new(),
+            &FinalVmSettings::default(),
+            &options(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(1, reports.len());
+        let Some(Err(AppInitModifyError::FinalVmError(step_error))) = &reports[0].correction else {
+            panic!("unexpected correction {:?}", reports[0].correction);
+        };
+        assert_eq!(ErrorKind::QuotaExceeded, step_error.kind);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::activities::Activities;
+use crate::error::fly_error;
+use crate::generated::obelisk_flyio::workflow::{
+    types::{AppInitModifyError, ExtraFile},
+    workflow::DeploymentOptions,
+};
+use crate::{
+    MINIO_VM_NAME, MINIO_VOLUME_NAME, VM_NAME_FINAL, VM_NAME_TEMP, VOLUME_NAME, allocate_ip,
+    create_volume, delete_temp_vm, prepare_volume, setup_volume,
+};
+
+/// Deployment steps already satisfied by an existing app.
+pub(crate) struct Progress {
+    pub(crate) ip_allocated: bool,
+    pub(crate) volume_id: Option<String>,
+    pub(crate) minio_volume: bool,
+    /// Left over by an interrupted volume setup.
+    pub(crate) temp_vm_id: Option<String>,
+    pub(crate) minio_vm_id: Option<String>,
+    pub(crate) final_vm: bool,
+}
+
+pub(crate) fn inspect_progress(
+    act: &impl Activities,
+    app_name: &str,
+) -> Result<Progress, AppInitModifyError> {
+    if act
+        .apps_get(app_name)
+        .map_err(|_| AppInitModifyError::AppNameGetError)?
+        .is_none()
+    {
+        return Err(AppInitModifyError::AppNotFound);
+    }
+    let ips = act
+        .ips_list(app_name)
+        .map_err(fly_error(AppInitModifyError::IpAllocateError, "list-ips"))?;
+    let volumes = act.volumes_list(app_name).map_err(fly_error(
+        AppInitModifyError::VolumeCreateError,
+        "list-volumes",
+    ))?;
+    let machines = act
+        .machines_list(app_name)
+        .map_err(fly_error(AppInitModifyError::TempVmError, "list-machines"))?;
+    let machine_id = |name: &str| {
+        machines
+            .iter()
+            .find(|machine| machine.name == name)
+            .map(|machine| machine.id.clone())
+    };
+    Ok(Progress {
+        ip_allocated: !ips.is_empty(),
+        volume_id: volumes
+            .iter()
+            .find(|volume| volume.name == VOLUME_NAME)
+            .map(|volume| volume.id.clone()),
+        minio_volume: volumes
+            .iter()
+            .any(|volume| volume.name == MINIO_VOLUME_NAME),
+        temp_vm_id: machine_id(VM_NAME_TEMP),
+        minio_vm_id: machine_id(MINIO_VM_NAME),
+        final_vm: machine_id(VM_NAME_FINAL).is_some(),
+    })
+}
+
+/// Continue `prepare` on an existing app, skipping the steps that are already satisfied.
+pub(crate) fn resume_prepare(
+    act: &impl Activities,
+    app_name: &str,
+    obelisk_toml: &str,
+    extra_files: &[ExtraFile],
+    options: &DeploymentOptions,
+) -> Result<(), AppInitModifyError> {
+    let progress = inspect_progress(act, app_name)?;
+    if !progress.ip_allocated {
+        allocate_ip(act, app_name)?;
+    }
+    if let Some(temp_vm_id) = progress.temp_vm_id {
+        delete_temp_vm(act, app_name, &temp_vm_id)?;
+    }
+    // Once the final VM is running the volume is attached to it and the configuration is in place.
+    if !progress.final_vm {
+        match progress.volume_id {
+            // The configuration might have been fixed since the failure, write the files again.
+            Some(volume_id) => prepare_volume(
+                act,
+                app_name,
+                &volume_id,
+                obelisk_toml,
+                extra_files,
+                options,
+            )?,
+            None => setup_volume(act, app_name, obelisk_toml, extra_files, options)?,
+        }
+    }
+    if !progress.minio_volume {
+        create_volume(act, app_name, MINIO_VOLUME_NAME, options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inspect_progress, resume_prepare};
+    use crate::fake::{APP, FakeFly, options};
+    use crate::generated::obelisk_flyio::workflow::types::AppInitModifyError;
+    use crate::{
+        FinalVmSettings, MINIO_VOLUME_NAME, OBELISK_TOML_PATH, VM_NAME_FINAL, allocate_ip,
+        minio_configure, minio_start, setup_volume, start_final_vm, start_temp_vm,
+    };
+
+    #[test]
+    fn resume_prepare_should_fail_without_app() {
+        let fly = FakeFly::default();
+        let err = resume_prepare(&fly, APP, "", &[], &options()).unwrap_err();
+        assert!(matches!(err, AppInitModifyError::AppNotFound));
+    }
+
+    #[test]
+    fn resume_prepare_should_continue_interrupted_volume_setup() {
+        let fly = FakeFly::with_app(APP);
+        allocate_ip(&fly, APP).unwrap();
+        fly.fail_exec("server verify", 1, "invalid configuration");
+        setup_volume(&fly, APP, "broken", &[], &options()).unwrap_err();
+        fly.clear_exec_failures();
+
+        resume_prepare(&fly, APP, "fixed", &[], &options()).unwrap();
+        let app = fly.app(APP);
+        assert_eq!(1, app.ips.len());
+        assert!(app.machines.is_empty());
+        assert_eq!(2, app.volumes.len());
+        assert!(
+            app.volumes
+                .iter()
+                .any(|volume| volume.name == MINIO_VOLUME_NAME)
+        );
+        let volume_id = app.volumes[0].id.clone();
+        drop(app);
+        assert_eq!(
+            Some("fixed".to_string()),
+            fly.file(&volume_id, OBELISK_TOML_PATH)
+        );
+    }
+
+    #[test]
+    fn resume_prepare_should_skip_satisfied_steps() {
+        let fly = FakeFly::with_app(APP);
+        allocate_ip(&fly, APP).unwrap();
+        setup_volume(&fly, APP, "", &[], &options()).unwrap();
+        crate::create_volume(&fly, APP, MINIO_VOLUME_NAME, &options()).unwrap();
+        let minio_vm_id = minio_start(&fly, APP, &options()).unwrap();
+        minio_configure(&fly, APP, &minio_vm_id).unwrap();
+        start_final_vm(&fly, APP, &options(), &FinalVmSettings::default()).unwrap();
+        let calls = fly.state().calls.len();
+
+        resume_prepare(&fly, APP, "", &[], &options()).unwrap();
+        let progress = inspect_progress(&fly, APP).unwrap();
+        assert_eq!(Some(minio_vm_id), progress.minio_vm_id);
+        assert!(progress.final_vm);
+        // Only the inspections were made, nothing was created or executed.
+        assert!(
+            fly.state().calls[calls..]
+                .iter()
+                .all(|call| call.contains("::list") || call.starts_with("apps::get"))
+        );
+        assert!(
+            fly.app(APP)
+                .machines
+                .iter()
+                .any(|machine| machine.name == VM_NAME_FINAL)
+        );
+    }
+
+    #[test]
+    fn inspect_progress_should_find_left_over_temp_vm() {
+        let fly = FakeFly::with_app(APP);
+        let volume_id = crate::create_volume(&fly, APP, "db", &options()).unwrap();
+        let temp_vm_id = start_temp_vm(&fly, APP, &volume_id, &options(), None).unwrap();
+        let progress = inspect_progress(&fly, APP).unwrap();
+        assert_eq!(Some(temp_vm_id), progress.temp_vm_id);
+        assert_eq!(Some(volume_id), progress.volume_id);
+        assert!(!progress.ip_allocated);
+        assert!(!progress.minio_volume);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::activities::Activities;
+use crate::destroy::delete_volume;
+use crate::generated::obelisk_flyio::workflow::types::{ResourceKind, ResourceResult};
+use hashbrown::HashSet;
+
+/// Resources created by the current execution, step by step.
+///
+/// Each step is wrapped in [`Saga::step`], which compares the app's resources before and after
+/// the step. The order of creation within a step is not observed, its resources are recorded
+/// as the app, IP addresses, volumes and machines. A failure then unwinds exactly what this
+/// execution created, in reverse order.
+#[derive(Default)]
+pub(crate) struct Saga {
+    created: Vec<(ResourceKind, String)>,
+    /// Kinds of resources that could not be listed, so created resources might be missing.
+    unknown: Vec<ResourceKind>,
+}
+
+/// Resources of the app, `None` if they could not be listed.
+struct Inventory {
+    app_exists: Option<bool>,
+    ips: Option<HashSet<String>>,
+    volumes: Option<HashSet<String>>,
+    machines: Option<HashSet<String>>,
+}
+
+impl Inventory {
+    fn new(act: &impl Activities, app_name: &str) -> Inventory {
+        let app_exists = act.apps_get(app_name).ok().map(|app| app.is_some());
+        let list = |res: Result<Vec<String>, String>| {
+            if app_exists == Some(false) {
+                // Nothing can exist in an app that does not.
+                Some(HashSet::new())
+            } else {
+                res.ok().map(HashSet::from_iter)
+            }
+        };
+        Inventory {
+            app_exists,
+            ips: list(
+                act.ips_list(app_name)
+                    .map(|ips| ips.into_iter().map(|ip_detail| ip_detail.ip).collect()),
+            ),
+            volumes: list(
+                act.volumes_list(app_name)
+                    .map(|volumes| volumes.into_iter().map(|volume| volume.id).collect()),
+            ),
+            machines: list(
+                act.machines_list(app_name)
+                    .map(|machines| machines.into_iter().map(|machine| machine.id).collect()),
+            ),
+        }
+    }
+}
+
+impl Saga {
+    /// Run the step, recording the resources it created even if it failed.
+    pub(crate) fn step<T, E>(
+        &mut self,
+        act: &impl Activities,
+        app_name: &str,
+        step: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let before = Inventory::new(act, app_name);
+        let res = step();
+        let after = Inventory::new(act, app_name);
+        match (before.app_exists, after.app_exists) {
+            (Some(false), Some(true)) => {
+                self.created.push((ResourceKind::App, app_name.to_string()));
+            }
+            (Some(_), Some(_)) => {}
+            _ => self.mark_unknown(ResourceKind::App),
+        }
+        // Machines are created last, so that they are compensated first.
+        for (kind, before, after) in [
+            (ResourceKind::Ip, before.ips, after.ips),
+            (ResourceKind::Volume, before.volumes, after.volumes),
+            (ResourceKind::Machine, before.machines, after.machines),
+        ] {
+            match (before, after) {
+                (Some(before), Some(after)) => {
+                    let mut created: Vec<_> = after.difference(&before).cloned().collect();
+                    created.sort();
+                    self.created
+                        .extend(created.into_iter().map(|id| (kind, id)));
+                }
+                _ => self.mark_unknown(kind),
+            }
+        }
+        res
+    }
+
+    fn mark_unknown(&mut self, kind: ResourceKind) {
+        if !self.unknown.contains(&kind) {
+            self.unknown.push(kind);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.unknown.is_empty()
+    }
+
+    /// Undo the created resources in reverse order of creation.
+    pub(crate) fn compensate(self, act: &impl Activities, app_name: &str) -> Vec<ResourceResult> {
+        let mut results: Vec<_> = self
+            .unknown
+            .into_iter()
+            .map(|kind| ResourceResult {
+                kind,
+                id: String::new(),
+                error: Some("cannot list resources, created resources are unknown".to_string()),
+            })
+            .collect();
+        for (kind, id) in self.created.into_iter().rev() {
+            let res = match kind {
+                ResourceKind::Machine => {
+                    // Ignore failure to shut down, the VM will be deleted with force.
+                    let _ = act.machines_stop(app_name, &id);
+                    act.machines_delete(app_name, &id, true)
+                }
+                ResourceKind::Volume => delete_volume(act, app_name, &id),
+                ResourceKind::Ip => act.ips_release(app_name, &id),
+                ResourceKind::App => act.apps_delete(app_name, true),
+                ResourceKind::Snapshot | ResourceKind::Secret => Ok(()),
+            };
+            results.push(ResourceResult {
+                kind,
+                id,
+                error: res.err(),
+            });
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Saga;
+    use crate::activities::Activities as _;
+    use crate::fake::{APP, FakeFly};
+    use crate::generated::obelisk_flyio::{
+        activity_fly_http::machines::MachineConfig,
+        workflow::types::{AppInitModifyError, ResourceKind},
+    };
+    use crate::{allocate_ip, app_create};
+
+    fn machine_config() -> MachineConfig {
+        MachineConfig {
+            image: "image".to_string(),
+            guest: None,
+            auto_destroy: None,
+            init: None,
+            env: None,
+            restart: None,
+            stop_config: None,
+            mounts: None,
+            services: None,
+        }
+    }
+
+    #[test]
+    fn compensate_should_unwind_created_resources_in_reverse_order() {
+        let fly = FakeFly::default();
+        let mut saga = Saga::default();
+        saga.step(&fly, APP, || {
+            app_create(&fly, "org", APP)?;
+            allocate_ip(&fly, APP)
+        })
+        .unwrap();
+        let err = saga
+            .step(&fly, APP, || {
+                fly.machines_create(APP, "obelisk", &machine_config(), None)
+                    .map_err(|_| AppInitModifyError::AppDeleted)?;
+                Err::<(), _>(AppInitModifyError::HealthCheckFailed)
+            })
+            .unwrap_err();
+        assert!(matches!(err, AppInitModifyError::HealthCheckFailed));
+
+        let results = saga.compensate(&fly, APP);
+        assert_eq!(
+            vec![ResourceKind::Machine, ResourceKind::Ip, ResourceKind::App],
+            results.iter().map(|result| result.kind).collect::<Vec<_>>()
+        );
+        assert!(results.iter().all(|result| result.error.is_none()));
+        assert!(fly.state().apps.is_empty());
+    }
+
+    #[test]
+    fn compensate_should_keep_resources_not_created_by_the_saga() {
+        let fly = FakeFly::with_app(APP);
+        let existing = fly
+            .machines_create(APP, "existing", &machine_config(), None)
+            .unwrap();
+        let mut saga = Saga::default();
+        saga.step(&fly, APP, || {
+            fly.machines_create(APP, "obelisk", &machine_config(), None)
+        })
+        .unwrap();
+        fly.fail("machines::delete", "503 Service Unavailable");
+
+        let results = saga.compensate(&fly, APP);
+        assert_eq!(1, results.len());
+        assert!(results[0].error.is_some());
+        let app = fly.app(APP);
+        assert_eq!(2, app.machines.len());
+        assert!(app.machines.iter().any(|machine| machine.id == existing));
+    }
+
+    #[test]
+    fn unknown_resources_should_be_reported() {
+        let fly = FakeFly::with_app(APP);
+        let mut saga = Saga::default();
+        fly.fail("volumes::list", "timed out");
+        saga.step(&fly, APP, || Ok::<(), ()>(())).unwrap();
+        assert!(!saga.is_empty());
+        let results = saga.compensate(&fly, APP);
+        assert_eq!(ResourceKind::Volume, results[0].kind);
+        assert!(results[0].error.is_some());
+    }
+
+    #[test]
+    fn failed_app_lookup_should_be_reported_as_unknown() {
+        let fly = FakeFly::default();
+        let mut saga = Saga::default();
+        fly.fail("apps::get", "connection reset");
+        saga.step(&fly, APP, || app_create(&fly, "org", APP))
+            .unwrap();
+        let results = saga.compensate(&fly, APP);
+        let app_result = results
+            .iter()
+            .find(|result| result.kind == ResourceKind::App)
+            .unwrap();
+        assert!(app_result.error.is_some());
+        // Not known to be created by the saga, so it is kept.
+        assert!(fly.calls("apps::delete").is_empty());
+    }
+}
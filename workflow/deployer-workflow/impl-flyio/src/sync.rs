@@ -0,0 +1,345 @@
+use crate::activities::Activities;
+use crate::generated::obelisk::types::time::{Duration as SchedulingDuration, ScheduleAt};
+use crate::generated::obelisk_flyio::workflow::types::{
+    RotatedSecret, SecretRotation, StepError, SyncSecretsError, SyncSecretsFailed,
+    SyncSecretsOptions,
+};
+use crate::transfer::put_file;
+use crate::{
+    SECRET_DIGESTS_PATH, VM_NAME_FINAL, check_health, exec_checked, find_machine_id,
+    restart_final_vm,
+};
+use std::collections::BTreeMap;
+
+/// Secret names and their digests.
+type Digests = BTreeMap<String, String>;
+
+/// Current digests of the referenced secrets, secrets that are not set are left out.
+fn current_digests(
+    act: &impl Activities,
+    app_name: &str,
+    secret_users: &BTreeMap<String, Vec<String>>,
+) -> Result<Digests, StepError> {
+    Ok(act
+        .secrets_list(app_name)
+        .map_err(|err| StepError::fly("list-secrets", err))?
+        .into_iter()
+        .filter(|secret| secret_users.contains_key(&secret.name))
+        .map(|secret| (secret.name, secret.digest))
+        .collect())
+}
+
+fn final_vm_id(act: &impl Activities, app_name: &str) -> Result<String, StepError> {
+    find_machine_id(act, app_name, VM_NAME_FINAL)
+        .map_err(|err| StepError::fly("list-machines", err))?
+        .ok_or_else(|| StepError::not_found("get-final-vm", "cannot find the final VM"))
+}
+
+/// Digests recorded on the volume, `None` if none were recorded yet.
+fn read_digests(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+) -> Result<Option<Digests>, StepError> {
+    // `test -e` exits with 1 only if the file is missing, other failures are errors.
+    let exists = act
+        .machines_exec(
+            app_name,
+            machine_id,
+            &[
+                "test".to_string(),
+                "-e".to_string(),
+                SECRET_DIGESTS_PATH.to_string(),
+            ],
+        )
+        .map_err(|err| StepError::fly("read-secret-digests", err).with_machine_id(machine_id))?;
+    match exists.exit_code {
+        Some(0) => {}
+        Some(1) => return Ok(None),
+        _ => {
+            return Err(StepError::exec(
+                "read-secret-digests",
+                machine_id,
+                "cannot check whether the digests were recorded".to_string(),
+                &exists,
+            ));
+        }
+    }
+    let exec_response = exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "read-secret-digests",
+        vec!["cat".to_string(), SECRET_DIGESTS_PATH.to_string()],
+    )?;
+    Ok(Some(
+        exec_response
+            .stdout
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, digest)| (name.to_string(), digest.to_string()))
+            .collect(),
+    ))
+}
+
+fn write_digests(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    digests: &Digests,
+) -> Result<(), StepError> {
+    let contents: String = digests
+        .iter()
+        .map(|(name, digest)| format!("{name}={digest}\n"))
+        .collect();
+    put_file(
+        act,
+        app_name,
+        machine_id,
+        SECRET_DIGESTS_PATH,
+        contents.as_bytes(),
+    )
+}
+
+/// Record the current digests of the referenced secrets on the volume of the final VM.
+pub(crate) fn store_secret_digests(
+    act: &impl Activities,
+    app_name: &str,
+    secret_users: &BTreeMap<String, Vec<String>>,
+) -> Result<(), StepError> {
+    let machine_id = final_vm_id(act, app_name)?;
+    let digests = current_digests(act, app_name, secret_users)?;
+    write_digests(act, app_name, &machine_id, &digests)
+}
+
+/// Compare the digests once, restarting the final VM if a referenced secret was rotated.
+fn sync_once(
+    act: &impl Activities,
+    app_name: &str,
+    secret_users: &BTreeMap<String, Vec<String>>,
+    check: u32,
+    health_check_deadline_secs: u16,
+) -> Result<Option<SecretRotation>, SyncSecretsError> {
+    let machine_id = final_vm_id(act, app_name).map_err(SyncSecretsError::DigestsError)?;
+    let recorded =
+        read_digests(act, app_name, &machine_id).map_err(SyncSecretsError::DigestsError)?;
+    let current =
+        current_digests(act, app_name, secret_users).map_err(SyncSecretsError::DigestsError)?;
+    let Some(recorded) = recorded else {
+        write_digests(act, app_name, &machine_id, &current)
+            .map_err(SyncSecretsError::DigestsError)?;
+        return Ok(None);
+    };
+    let secrets: Vec<_> = secret_users
+        .iter()
+        .filter(|(name, _)| recorded.get(*name) != current.get(*name))
+        .map(|(name, components)| RotatedSecret {
+            name: name.clone(),
+            components: components.clone(),
+        })
+        .collect();
+    if secrets.is_empty() {
+        return Ok(None);
+    }
+    let rotation = SecretRotation { check, secrets };
+    // Keep the recorded digests on failure, so that the next comparison restarts the VM again.
+    if let Err(error) = restart_final_vm(act, app_name)
+        .and_then(|()| check_health(act, app_name, health_check_deadline_secs))
+    {
+        return Err(SyncSecretsError::RestartFailed(SyncSecretsFailed {
+            rotation,
+            error,
+        }));
+    }
+    // Restarting keeps the machine ID.
+    write_digests(act, app_name, &machine_id, &current).map_err(SyncSecretsError::DigestsError)?;
+    Ok(Some(rotation))
+}
+
+/// Compare the digests once or periodically, returning the rotations that caused a restart.
+pub(crate) fn sync_secrets(
+    act: &impl Activities,
+    app_name: &str,
+    secret_users: &BTreeMap<String, Vec<String>>,
+    options: &SyncSecretsOptions,
+) -> Result<Vec<SecretRotation>, SyncSecretsError> {
+    let interval_secs = options.interval_secs()?;
+    let mut rotations = Vec::new();
+    let mut check = 0;
+    loop {
+        if let Some(interval_secs) = interval_secs
+            && check > 0
+        {
+            act.sleep(ScheduleAt::In(SchedulingDuration::Seconds(u64::from(
+                interval_secs,
+            ))));
+        }
+        check += 1;
+        if matches!(act.apps_get(app_name), Ok(None)) {
+            return Err(SyncSecretsError::AppDeleted);
+        }
+        rotations.extend(sync_once(
+            act,
+            app_name,
+            secret_users,
+            check,
+            options.health_check_deadline_secs,
+        )?);
+        if check >= options.max_checks() {
+            return Ok(rotations);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{store_secret_digests, sync_secrets};
+    use crate::activities::Activities as _;
+    use crate::fake::{APP, FakeFly, deployed};
+    use crate::generated::obelisk::types::time::ScheduleAt;
+    use crate::generated::obelisk_flyio::workflow::types::{
+        AppInitModifyError, SyncSecretsError, SyncSecretsOptions,
+    };
+    use std::collections::BTreeMap;
+
+    /// Deployed app using two secrets.
+    fn deployed_with_secrets() -> FakeFly {
+        let fly = deployed("");
+        rotate(&fly, "GITHUB_TOKEN");
+        rotate(&fly, "OPENAI_API_KEY");
+        fly.set_healthy_since(0);
+        fly
+    }
+
+    /// Set the secret or change its digest now.
+    fn rotate(fly: &FakeFly, secret: &str) {
+        let now = fly.state().now;
+        fly.set_secret_at(APP, secret, now);
+        fly.sleep(ScheduleAt::Now);
+    }
+
+    fn secret_users() -> BTreeMap<String, Vec<String>> {
+        BTreeMap::from([
+            (
+                "GITHUB_TOKEN".to_string(),
+                vec!["activity_github".to_string(), "webhook".to_string()],
+            ),
+            (
+                "OPENAI_API_KEY".to_string(),
+                vec!["activity_llm".to_string()],
+            ),
+        ])
+    }
+
+    fn sync_options(interval_secs: Option<u32>, max_checks: Option<u32>) -> SyncSecretsOptions {
+        SyncSecretsOptions {
+            interval_secs,
+            max_checks,
+            health_check_deadline_secs: 30,
+        }
+    }
+
+    #[test]
+    fn first_sync_should_record_digests_without_restart() {
+        let fly = deployed_with_secrets();
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap();
+        assert!(rotations.is_empty());
+        assert!(fly.calls("machines::restart").is_empty());
+
+        rotate(&fly, "OPENAI_API_KEY");
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap();
+        assert_eq!(1, rotations.len());
+    }
+
+    #[test]
+    fn rotated_secret_should_restart_the_final_vm() {
+        let fly = deployed_with_secrets();
+        store_secret_digests(&fly, APP, &secret_users()).unwrap();
+        let start = fly.state().now;
+        // Rotated before the third comparison.
+        fly.set_secret_at(APP, "GITHUB_TOKEN", start + 90);
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(Some(60), Some(4))).unwrap();
+        assert_eq!(1, rotations.len());
+        assert_eq!(3, rotations[0].check);
+        assert_eq!(1, rotations[0].secrets.len());
+        assert_eq!("GITHUB_TOKEN", rotations[0].secrets[0].name);
+        assert_eq!(
+            vec!["activity_github".to_string(), "webhook".to_string()],
+            rotations[0].secrets[0].components
+        );
+        // The new digests were recorded, the fourth comparison found no rotation.
+        assert_eq!(1, fly.calls("machines::restart").len());
+    }
+
+    #[test]
+    fn failed_restart_should_keep_recorded_digests() {
+        let fly = deployed_with_secrets();
+        store_secret_digests(&fly, APP, &secret_users()).unwrap();
+        rotate(&fly, "OPENAI_API_KEY");
+        fly.set_healthy_since(u64::MAX);
+        let err = sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap_err();
+        let SyncSecretsError::RestartFailed(failed) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("OPENAI_API_KEY", failed.rotation.secrets[0].name);
+        assert!(matches!(
+            failed.error,
+            AppInitModifyError::HealthCheckFailed
+        ));
+
+        fly.set_healthy_since(0);
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap();
+        assert_eq!(1, rotations.len());
+        assert_eq!(2, fly.calls("machines::restart").len());
+    }
+
+    #[test]
+    fn zero_interval_should_be_rejected() {
+        let fly = deployed_with_secrets();
+        let execs = fly.calls("machines::exec").len();
+        let err =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(Some(0), None)).unwrap_err();
+        assert!(matches!(err, SyncSecretsError::InvalidOptions(_)));
+        assert_eq!(execs, fly.calls("machines::exec").len());
+    }
+
+    #[test]
+    fn periodic_sync_should_finish_after_default_max_checks() {
+        let fly = deployed_with_secrets();
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(Some(60), None)).unwrap();
+        assert!(rotations.is_empty());
+        assert_eq!(1440, fly.calls("secrets::list").len());
+    }
+
+    #[test]
+    fn failed_read_should_not_be_taken_for_missing_digests() {
+        let fly = deployed_with_secrets();
+        store_secret_digests(&fly, APP, &secret_users()).unwrap();
+        rotate(&fly, "OPENAI_API_KEY");
+        fly.fail_exec("cat ", 1, "cat: read error: I/O error");
+        let err = sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap_err();
+        let SyncSecretsError::DigestsError(step_error) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!("read-secret-digests", step_error.step);
+
+        // The recorded digests were not overwritten, the rotation is still detected.
+        fly.clear_exec_failures();
+        let rotations =
+            sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap();
+        assert_eq!(1, rotations.len());
+    }
+
+    #[test]
+    fn sync_should_stop_on_app_deletion() {
+        let fly = FakeFly::default();
+        let err = sync_secrets(&fly, APP, &secret_users(), &sync_options(None, None)).unwrap_err();
+        assert!(matches!(err, SyncSecretsError::AppDeleted));
+    }
+}
@@ -1,50 +1,97 @@
-use crate::generated::obelisk_flyio::workflow::types::ObeliskConfig;
-use crate::{HEALTHCHECK_INTERNAL_PORT, VOLUME_MOUNT_PATH, WEBHOOK_INTERNAL_PORT};
-use anyhow::{Context, anyhow};
+use crate::generated::obelisk_flyio::workflow::types::{
+    BlockingStrategy, EnvVar, ExtraFile, HttpExposure, LogRotation, LogStyle, ObeliskConfig,
+    OutputForwarding, ServerTuning,
+};
+use crate::{
+    API_INTERNAL_PORT, API_PROXY_SERVER_NAME, API_PROXY_WEBHOOK_NAME, CODEGEN_CACHE_DIRECTORY,
+    EXTRA_FILES_BACKUP_DIR, HEALTHCHECK_EXTERNAL_PORT, HEALTHCHECK_INTERNAL_PORT, LITESTREAM_DIR,
+    LITESTREAM_YML_PATH, LOG_DIRECTORY, MINIO_BUCKET_NAME, OBELISK_TOML_BACKUP_PATH,
+    OBELISK_TOML_PATH, SECRET_DIGESTS_PATH, SQLITE_DIRECTORY, SQLITE_FILE_PATH, VOLUME_MOUNT_PATH,
+    WASM_CACHE_DIRECTORY, WEBHOOK_SERVER_NAME, WEBUI_INTERNAL_PORT, api_auth_secret, external_port,
+    get_api_proxy, get_http_servers,
+};
+use anyhow::{Context, anyhow, bail};
+use hashbrown::{HashMap, HashSet};
+use std::ops::RangeInclusive;
 use toml::Table; // Explicitly import Table
-pub(crate) fn serialize_obelisk_toml(config: &ObeliskConfig) -> Result<String, anyhow::Error> {
-    const HEALTHCHECK_SERVER_NAME: &str = "healthcheck_server";
-    const WEBHOOK_SERVER_NAME: &str = "webhook_server";
 
+const HEALTHCHECK_WEBHOOK_NAME: &str = "webhook_healthcheck";
+const HEALTHCHECK_SERVER_NAME: &str = "healthcheck_server";
+const MAX_LOCK_EXPIRY_SECS: u32 = 86_400;
+const MAX_RETRIES: u32 = 100;
+const MAX_RETRY_EXP_BACKOFF_MS: u32 = 3_600_000;
+const MAX_INFLIGHT_INSTANCES: u32 = 10_000;
+const DEFAULT_LOG_LEVEL: &str = "WARN,obelisk=info";
+/// Keys of `obelisk.toml` the deployer depends on, as paths of nested tables.
+const RESERVED_KEYS: &[&[&str]] = &[
+    &["sqlite", "directory"],
+    &["wasm", "cache_directory"],
+    &["wasm", "codegen_cache", "directory"],
+    &["api", "listening_addr"],
+    &["webui", "listening_addr"],
+    &["http_server"],
+    &["activity_wasm"],
+    &["workflow"],
+    &["webhook_endpoint"],
+    &["activity_stub"],
+    &["cron"],
+];
+/// Files and directories on the volume written by the deployer or by Obelisk.
+const RESERVED_PATHS: &[&str] = &[
+    OBELISK_TOML_PATH,
+    OBELISK_TOML_BACKUP_PATH,
+    EXTRA_FILES_BACKUP_DIR,
+    SECRET_DIGESTS_PATH,
+    LITESTREAM_YML_PATH,
+    LITESTREAM_DIR,
+    SQLITE_DIRECTORY,
+    WASM_CACHE_DIRECTORY,
+    CODEGEN_CACHE_DIRECTORY,
+    LOG_DIRECTORY,
+];
+
+pub(crate) fn serialize_obelisk_toml(config: &ObeliskConfig) -> Result<String, anyhow::Error> {
     let initial_toml_template = format!(
         r#"
-sqlite.directory = "{VOLUME_MOUNT_PATH}/obelisk-sqlite"
-wasm.cache_directory = "{VOLUME_MOUNT_PATH}/wasm"
-wasm.codegen_cache.directory = "{VOLUME_MOUNT_PATH}/codegen"
+sqlite.directory = "{SQLITE_DIRECTORY}"
+wasm.cache_directory = "{WASM_CACHE_DIRECTORY}"
+wasm.codegen_cache.directory = "{CODEGEN_CACHE_DIRECTORY}"
 
 wasm.parallel_compilation = false
 wasm.backtrace.persist = false # Speed up execution
 
-api.listening_addr = "[::]:5005"
-webui.listening_addr = "[::]:8080"
+api.listening_addr = "[::]:{API_INTERNAL_PORT}"
+webui.listening_addr = "[::]:{WEBUI_INTERNAL_PORT}"
 
 sqlite.pragma = {{ "cache_size" = "3000" }}
 
 [log.stdout]
 enabled = true
-level = "WARN,obelisk=info"
+level = "{DEFAULT_LOG_LEVEL}"
 
 [[http_server]]
 name = "{HEALTHCHECK_SERVER_NAME}"
 listening_addr = "0.0.0.0:{HEALTHCHECK_INTERNAL_PORT}"
 
 [[webhook_endpoint]]
-name = "webhook_healthcheck"
+name = "{HEALTHCHECK_WEBHOOK_NAME}"
 location.oci = "docker.io/getobelisk/components_flyio_webhook_healthcheck:2025-10-01@sha256:6fbc11b80b441ae6e642327b1ec0ceba85b2868d85dbce2d99d0d7b14a525c8c"
 http_server = "{HEALTHCHECK_SERVER_NAME}"
 routes = [""]
 
-[[http_server]]
-name = "{WEBHOOK_SERVER_NAME}"
-listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
-
 "#
     );
 
+    validate(config)?;
+
     let mut root_table = initial_toml_template
         .parse::<Table>()
         .map_err(|e| anyhow!("Failed to parse static TOML: {}", e))?;
 
+    if let Some(server_tuning) = &config.server_tuning {
+        apply_server_tuning(&mut root_table, server_tuning)?;
+    }
+
     fn get_or_create_array_of_tables<'a>(
         table: &'a mut Table,
         key: &str,
@@ -56,6 +103,25 @@ listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
             .with_context(|| format!("Expected '{key}' to be an array of tables"))
     }
 
+    // Add http_server
+    let http_server_array = get_or_create_array_of_tables(&mut root_table, "http_server")?;
+    for http_server in get_http_servers(config) {
+        // Addresses of the private network are IPv6 only.
+        let listening_addr = match http_server.exposure {
+            HttpExposure::PublicTls | HttpExposure::PublicHttp => {
+                format!("0.0.0.0:{}", http_server.internal_port)
+            }
+            HttpExposure::PrivateOnly => format!("[::]:{}", http_server.internal_port),
+        };
+        let mut server_table = Table::new();
+        server_table.insert("name".to_string(), toml::Value::String(http_server.name));
+        server_table.insert(
+            "listening_addr".to_string(),
+            toml::Value::String(listening_addr),
+        );
+        http_server_array.push(toml::Value::Table(server_table));
+    }
+
     // Add activity_wasm
     if let Some(activities) = &config.activity_wasm_list {
         let activity_array = get_or_create_array_of_tables(&mut root_table, "activity_wasm")?;
@@ -76,26 +142,37 @@ listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
             if let Some(env_vars) = &activity.env_vars {
                 activity_table.insert(
                     "env_vars".to_string(),
-                    toml::Value::Array(
-                        env_vars
-                            .iter()
-                            .map(|v| toml::Value::String(v.clone()))
-                            .collect(),
-                    ),
+                    toml::Value::Array(env_vars.iter().map(env_var_entry).collect()),
                 );
             }
-            if let Some(lock_expiry) = activity.lock_expiry_seconds {
-                let mut exec_table = Table::new();
-                let mut lock_expiry_table = Table::new();
-                lock_expiry_table.insert(
-                    "seconds".to_string(),
-                    toml::Value::Integer(lock_expiry as i64),
+            if let Some(max_retries) = activity.max_retries {
+                activity_table.insert(
+                    "max_retries".to_string(),
+                    toml::Value::Integer(max_retries as i64),
                 );
-                exec_table.insert(
-                    "lock_expiry".to_string(),
-                    toml::Value::Table(lock_expiry_table),
+            }
+            if let Some(backoff_ms) = activity.retry_exp_backoff_ms {
+                activity_table.insert(
+                    "retry_exp_backoff".to_string(),
+                    milliseconds_table(backoff_ms),
                 );
-                activity_table.insert("exec".to_string(), toml::Value::Table(exec_table));
+            }
+            if let Some(retry_on_err) = activity.retry_on_err {
+                activity_table.insert(
+                    "retry_on_err".to_string(),
+                    toml::Value::Boolean(retry_on_err),
+                );
+            }
+            insert_forwarding(
+                &mut activity_table,
+                activity.forward_stdout,
+                activity.forward_stderr,
+            );
+            if let Some(exec_table) = exec_table(
+                activity.lock_expiry_seconds,
+                activity.max_inflight_instances,
+            ) {
+                activity_table.insert("exec".to_string(), exec_table);
             }
             activity_array.push(toml::Value::Table(activity_table));
         }
@@ -118,6 +195,35 @@ listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
             );
             workflow_table.insert("location".to_string(), toml::Value::Table(location_table));
 
+            if let Some(backoff_ms) = workflow.retry_exp_backoff_ms {
+                workflow_table.insert(
+                    "retry_exp_backoff".to_string(),
+                    milliseconds_table(backoff_ms),
+                );
+            }
+            if let Some(retry_on_trap) = workflow.retry_on_trap {
+                workflow_table.insert(
+                    "retry_on_trap".to_string(),
+                    toml::Value::Boolean(retry_on_trap),
+                );
+            }
+            if let Some(blocking_strategy) = workflow.blocking_strategy {
+                let blocking_strategy = match blocking_strategy {
+                    BlockingStrategy::Interrupt => "interrupt",
+                    BlockingStrategy::Await => "await",
+                };
+                workflow_table.insert(
+                    "blocking_strategy".to_string(),
+                    toml::Value::String(blocking_strategy.to_string()),
+                );
+            }
+            if let Some(exec_table) = exec_table(
+                workflow.lock_expiry_seconds,
+                workflow.max_inflight_instances,
+            ) {
+                workflow_table.insert("exec".to_string(), exec_table);
+            }
+
             workflow_array.push(toml::Value::Table(workflow_table));
         }
     }
@@ -141,7 +247,13 @@ listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
 
             webhook_table.insert(
                 "http_server".to_string(),
-                toml::Value::String(WEBHOOK_SERVER_NAME.to_string()),
+                toml::Value::String(
+                    webhook
+                        .http_server
+                        .as_deref()
+                        .unwrap_or(WEBHOOK_SERVER_NAME)
+                        .to_string(),
+                ),
             );
 
             let routes_array: Vec<toml::Value> = webhook
@@ -169,30 +281,559 @@ listening_addr = "0.0.0.0:{WEBHOOK_INTERNAL_PORT}"
             if let Some(env_vars) = &webhook.env_vars {
                 webhook_table.insert(
                     "env_vars".to_string(),
-                    toml::Value::Array(
-                        env_vars
-                            .iter()
-                            .map(|v| toml::Value::String(v.clone()))
-                            .collect(),
-                    ),
+                    toml::Value::Array(env_vars.iter().map(env_var_entry).collect()),
                 );
             }
+            insert_forwarding(
+                &mut webhook_table,
+                webhook.forward_stdout,
+                webhook.forward_stderr,
+            );
             webhook_array.push(toml::Value::Table(webhook_table));
         }
     }
 
+    // Add the proxy webhook in front of the WebUI, the gRPC API is not proxied.
+    if let Some(api_proxy) = get_api_proxy(config) {
+        let webhook_array = get_or_create_array_of_tables(&mut root_table, "webhook_endpoint")?;
+        let mut webhook_table = Table::new();
+        webhook_table.insert(
+            "name".to_string(),
+            toml::Value::String(API_PROXY_WEBHOOK_NAME.to_string()),
+        );
+        let mut location_table = Table::new();
+        location_table.insert(
+            "oci".to_string(),
+            toml::Value::String(api_proxy.location_oci.clone()),
+        );
+        webhook_table.insert("location".to_string(), toml::Value::Table(location_table));
+        webhook_table.insert(
+            "http_server".to_string(),
+            toml::Value::String(API_PROXY_SERVER_NAME.to_string()),
+        );
+        webhook_table.insert(
+            "routes".to_string(),
+            toml::Value::Array(vec![toml::Value::String(String::new())]),
+        );
+        webhook_table.insert(
+            "env_vars".to_string(),
+            toml::Value::Array(vec![
+                toml::Value::String(api_auth_secret(api_proxy.auth).to_string()),
+                toml::Value::String(format!(
+                    "API_PROXY_UPSTREAM=http://[::1]:{WEBUI_INTERNAL_PORT}"
+                )),
+            ]),
+        );
+        webhook_array.push(toml::Value::Table(webhook_table));
+    }
+
+    // Add activity_stub
+    if let Some(stubs) = &config.activity_stub_list {
+        let stub_array = get_or_create_array_of_tables(&mut root_table, "activity_stub")?;
+        for stub in stubs {
+            let mut stub_table = Table::new();
+            stub_table.insert("name".to_string(), toml::Value::String(stub.name.clone()));
+
+            let mut location_table = Table::new();
+            location_table.insert(
+                "oci".to_string(),
+                toml::Value::String(stub.location_oci.clone()),
+            );
+            stub_table.insert("location".to_string(), toml::Value::Table(location_table));
+
+            stub_array.push(toml::Value::Table(stub_table));
+        }
+    }
+
+    // Add cron
+    if let Some(crons) = &config.cron_list {
+        let cron_array = get_or_create_array_of_tables(&mut root_table, "cron")?;
+        for cron in crons {
+            let mut cron_table = Table::new();
+            cron_table.insert("name".to_string(), toml::Value::String(cron.name.clone()));
+            cron_table.insert(
+                "schedule".to_string(),
+                toml::Value::String(cron.schedule.clone()),
+            );
+            cron_table.insert("ffqn".to_string(), toml::Value::String(cron.ffqn.clone()));
+
+            let params = match &cron.params {
+                Some(params) => format!("params = {params}")
+                    .parse::<Table>()
+                    .ok()
+                    .and_then(|mut table| table.remove("params"))
+                    .filter(toml::Value::is_array)
+                    .with_context(|| {
+                        format!("params of cron '{}' must be an inline array", cron.name)
+                    })?,
+                None => toml::Value::Array(Vec::new()),
+            };
+            cron_table.insert("params".to_string(), params);
+
+            cron_array.push(toml::Value::Table(cron_table));
+        }
+    }
+
+    if let Some(overlay) = &config.toml_overlay {
+        let overlay = overlay
+            .parse::<Table>()
+            .context("toml-overlay is not valid TOML")?;
+        merge_overlay(&mut root_table, overlay, &mut Vec::new())?;
+    }
+
     Ok(toml::to_string_pretty(&toml::Value::Table(root_table))?)
 }
 
+/// Table at the path of nested tables, created if missing.
+fn get_or_create_table<'a>(
+    mut table: &'a mut Table,
+    path: &[&str],
+) -> Result<&'a mut Table, anyhow::Error> {
+    for key in path {
+        table = table
+            .entry(*key)
+            .or_insert_with(|| toml::Value::Table(Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("Expected '{key}' to be a table"))?;
+    }
+    Ok(table)
+}
+
+/// Override the defaults of the template.
+fn apply_server_tuning(
+    root_table: &mut Table,
+    server_tuning: &ServerTuning,
+) -> Result<(), anyhow::Error> {
+    let log_level = server_tuning
+        .log_level
+        .as_deref()
+        .unwrap_or(DEFAULT_LOG_LEVEL);
+    let stdout_table = get_or_create_table(root_table, &["log", "stdout"])?;
+    stdout_table.insert(
+        "level".to_string(),
+        toml::Value::String(log_level.to_string()),
+    );
+    if let Some(style) = server_tuning.log_style {
+        stdout_table.insert("style".to_string(), log_style(style));
+    }
+    if let Some(log_file) = &server_tuning.log_file {
+        let file_table = get_or_create_table(root_table, &["log", "file"])?;
+        file_table.insert("enabled".to_string(), toml::Value::Boolean(true));
+        file_table.insert(
+            "directory".to_string(),
+            toml::Value::String(LOG_DIRECTORY.to_string()),
+        );
+        file_table.insert(
+            "level".to_string(),
+            toml::Value::String(log_file.level.as_deref().unwrap_or(log_level).to_string()),
+        );
+        if let Some(style) = log_file.style {
+            file_table.insert("style".to_string(), log_style(style));
+        }
+        let rotation = match log_file.rotation.unwrap_or(LogRotation::Daily) {
+            LogRotation::Minutely => "minutely",
+            LogRotation::Hourly => "hourly",
+            LogRotation::Daily => "daily",
+            LogRotation::Never => "never",
+        };
+        file_table.insert(
+            "rotation".to_string(),
+            toml::Value::String(rotation.to_string()),
+        );
+    }
+    if let Some(endpoint) = &server_tuning.otlp_endpoint {
+        let otlp_table = get_or_create_table(root_table, &["otlp"])?;
+        otlp_table.insert("enabled".to_string(), toml::Value::Boolean(true));
+        otlp_table.insert(
+            "otlp_endpoint".to_string(),
+            toml::Value::String(endpoint.clone()),
+        );
+    }
+    if let Some(persist) = server_tuning.backtrace_persist {
+        get_or_create_table(root_table, &["wasm", "backtrace"])?
+            .insert("persist".to_string(), toml::Value::Boolean(persist));
+    }
+    if let Some(pragmas) = &server_tuning.sqlite_pragmas {
+        let pragma_table = get_or_create_table(root_table, &["sqlite", "pragma"])?;
+        for (name, value) in pragmas {
+            pragma_table.insert(name.clone(), toml::Value::String(value.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn log_style(style: LogStyle) -> toml::Value {
+    let style = match style {
+        LogStyle::Plain => "plain",
+        LogStyle::PlainCompact => "plain_compact",
+        LogStyle::Json => "json",
+    };
+    toml::Value::String(style.to_string())
+}
+
+/// Merge tables recursively, other values of the overlay replace the generated ones.
+fn merge_overlay(
+    table: &mut Table,
+    overlay: Table,
+    path: &mut Vec<String>,
+) -> Result<(), anyhow::Error> {
+    for (key, value) in overlay {
+        path.push(key.clone());
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(overlay)) => {
+                merge_overlay(table, overlay, path)?;
+            }
+            (_, value) => {
+                // Replacing a table would drop the reserved keys it contains.
+                if RESERVED_KEYS.iter().any(|reserved| {
+                    reserved.len() >= path.len()
+                        && reserved.iter().zip(path.iter()).all(|(a, b)| a == b)
+                }) {
+                    bail!(
+                        "toml-overlay must not set '{}', it is set by the deployer",
+                        path.join(".")
+                    );
+                }
+                table.insert(key, value);
+            }
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn milliseconds_table(milliseconds: u32) -> toml::Value {
+    let mut table = Table::new();
+    table.insert(
+        "milliseconds".to_string(),
+        toml::Value::Integer(milliseconds as i64),
+    );
+    toml::Value::Table(table)
+}
+
+fn exec_table(
+    lock_expiry_seconds: Option<u32>,
+    max_inflight_instances: Option<u32>,
+) -> Option<toml::Value> {
+    let mut exec_table = Table::new();
+    if let Some(lock_expiry) = lock_expiry_seconds {
+        let mut lock_expiry_table = Table::new();
+        lock_expiry_table.insert(
+            "seconds".to_string(),
+            toml::Value::Integer(lock_expiry as i64),
+        );
+        exec_table.insert(
+            "lock_expiry".to_string(),
+            toml::Value::Table(lock_expiry_table),
+        );
+    }
+    if let Some(max_inflight_instances) = max_inflight_instances {
+        exec_table.insert(
+            "max_inflight_instances".to_string(),
+            toml::Value::Integer(max_inflight_instances as i64),
+        );
+    }
+    (!exec_table.is_empty()).then_some(toml::Value::Table(exec_table))
+}
+
+fn insert_forwarding(
+    table: &mut Table,
+    forward_stdout: Option<OutputForwarding>,
+    forward_stderr: Option<OutputForwarding>,
+) {
+    for (key, forwarding) in [
+        ("forward_stdout", forward_stdout),
+        ("forward_stderr", forward_stderr),
+    ] {
+        if let Some(forwarding) = forwarding {
+            let forwarding = match forwarding {
+                OutputForwarding::None => "none",
+                OutputForwarding::Stdout => "stdout",
+                OutputForwarding::Stderr => "stderr",
+            };
+            table.insert(key.to_string(), toml::Value::String(forwarding.to_string()));
+        }
+    }
+}
+
+fn check_range(
+    component_name: &str,
+    key: &str,
+    value: Option<u32>,
+    range: RangeInclusive<u32>,
+) -> Result<(), anyhow::Error> {
+    match value {
+        Some(value) if !range.contains(&value) => bail!(
+            "{key} of '{component_name}' must be between {} and {}, got {value}",
+            range.start(),
+            range.end()
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Entry of `env_vars`: variables present in the VM's environment are forwarded by name,
+/// other literals are written as `key=value`.
+fn env_var_entry(env_var: &EnvVar) -> toml::Value {
+    toml::Value::String(match env_var {
+        EnvVar::Secret(key) | EnvVar::MachineEnv(key) => key.clone(),
+        EnvVar::Literal(literal) if literal.in_machine_env => literal.key.clone(),
+        EnvVar::Literal(literal) => format!("{}={}", literal.key, literal.value),
+    })
+}
+
+/// Keys must be usable as names of environment variables.
+/// Literals set in the final VM's environment must agree across components
+/// and must not override the Litestream settings.
+fn check_env_vars(
+    component_name: &str,
+    env_vars: Option<&Vec<EnvVar>>,
+    machine_env: &mut HashMap<String, String>,
+) -> Result<(), anyhow::Error> {
+    for env_var in env_vars.into_iter().flatten() {
+        let key = match env_var {
+            EnvVar::Secret(key) | EnvVar::MachineEnv(key) => key,
+            EnvVar::Literal(literal) => &literal.key,
+        };
+        if key.is_empty() || key.contains('=') {
+            bail!("invalid environment variable name '{key}' of '{component_name}'");
+        }
+        if let EnvVar::Literal(literal) = env_var
+            && literal.in_machine_env
+        {
+            if key.starts_with("LITESTREAM_") {
+                bail!("environment variable '{key}' of '{component_name}' is reserved");
+            }
+            if let Some(value) = machine_env.insert(key.clone(), literal.value.clone())
+                && value != literal.value
+            {
+                bail!("environment variable '{key}' of '{component_name}' has conflicting values");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_server_tuning(server_tuning: &ServerTuning) -> Result<(), anyhow::Error> {
+    let log_file_level = server_tuning
+        .log_file
+        .as_ref()
+        .and_then(|log_file| log_file.level.as_ref());
+    for level in [server_tuning.log_level.as_ref(), log_file_level]
+        .into_iter()
+        .flatten()
+    {
+        if level.trim().is_empty() {
+            bail!("log level must not be empty");
+        }
+    }
+    if let Some(endpoint) = &server_tuning.otlp_endpoint
+        && !endpoint.starts_with("http://")
+        && !endpoint.starts_with("https://")
+    {
+        bail!("OTLP endpoint '{endpoint}' must be an http:// or https:// URL");
+    }
+    for (name, _) in server_tuning.sqlite_pragmas.iter().flatten() {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("invalid SQLite pragma name '{name}'");
+        }
+    }
+    Ok(())
+}
+
+/// Names and ports of the HTTP servers must be unique and must not collide with the deployer's servers.
+fn check_http_servers(config: &ObeliskConfig) -> Result<(), anyhow::Error> {
+    let http_servers = get_http_servers(config);
+    let mut names: HashSet<&str> = HashSet::from_iter([HEALTHCHECK_SERVER_NAME]);
+    let mut internal_ports: HashSet<u16> = HashSet::from_iter([
+        HEALTHCHECK_INTERNAL_PORT,
+        API_INTERNAL_PORT,
+        WEBUI_INTERNAL_PORT,
+    ]);
+    let mut external_ports: HashSet<u16> = HashSet::from_iter([HEALTHCHECK_EXTERNAL_PORT]);
+    for http_server in &http_servers {
+        let name = http_server.name.as_str();
+        if name.is_empty() {
+            bail!("HTTP server name must not be empty");
+        }
+        if !names.insert(name) {
+            bail!("HTTP server name '{name}' is not unique");
+        }
+        if http_server.exposure == HttpExposure::PrivateOnly && http_server.external_port.is_some()
+        {
+            bail!("HTTP server '{name}' is private-only and must not have an external port");
+        }
+        let ports = [
+            (
+                "internal",
+                Some(http_server.internal_port),
+                &mut internal_ports,
+            ),
+            ("external", external_port(http_server), &mut external_ports),
+        ];
+        for (kind, port, used_ports) in ports {
+            if let Some(port) = port
+                && (port == 0 || !used_ports.insert(port))
+            {
+                bail!("{kind} port {port} of HTTP server '{name}' is not available");
+            }
+        }
+    }
+    for webhook in config.webhook_endpoint_list.iter().flatten() {
+        let http_server = webhook
+            .http_server
+            .as_deref()
+            .unwrap_or(WEBHOOK_SERVER_NAME);
+        if !http_servers.iter().any(|server| server.name == http_server) {
+            bail!(
+                "HTTP server '{http_server}' of '{}' is not defined",
+                webhook.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Extra files must be placed under the volume's mount path without overwriting the deployer's files.
+fn check_extra_files(extra_files: Option<&Vec<ExtraFile>>) -> Result<(), anyhow::Error> {
+    let mut paths = HashSet::new();
+    for extra_file in extra_files.into_iter().flatten() {
+        let path = extra_file.path.as_str();
+        let Some(relative_path) = path
+            .strip_prefix(VOLUME_MOUNT_PATH)
+            .and_then(|path| path.strip_prefix('/'))
+        else {
+            bail!("extra file '{path}' must be placed under {VOLUME_MOUNT_PATH}");
+        };
+        if relative_path
+            .split('/')
+            .any(|segment| matches!(segment, "" | "." | ".."))
+        {
+            bail!("path of extra file '{path}' must be normalized");
+        }
+        if RESERVED_PATHS.iter().any(|reserved| {
+            path == *reserved
+                || path
+                    .strip_prefix(reserved)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }) {
+            bail!("extra file '{path}' would overwrite a file managed by the deployer");
+        }
+        if !paths.insert(path) {
+            bail!("extra file '{path}' is not unique");
+        }
+    }
+    Ok(())
+}
+
+/// Reject values that Obelisk would refuse, so that nothing is created on Fly.io.
+fn validate(config: &ObeliskConfig) -> Result<(), anyhow::Error> {
+    let mut names = HashSet::new();
+    names.insert(HEALTHCHECK_WEBHOOK_NAME.to_string());
+    names.insert(API_PROXY_WEBHOOK_NAME.to_string());
+    let mut check_name = |name: &str| {
+        if name.is_empty() {
+            bail!("component name must not be empty");
+        }
+        if !names.insert(name.to_string()) {
+            bail!("component name '{name}' is not unique");
+        }
+        Ok(())
+    };
+    let mut machine_env = HashMap::new();
+    for activity in config.activity_wasm_list.iter().flatten() {
+        check_name(&activity.name)?;
+        check_env_vars(&activity.name, activity.env_vars.as_ref(), &mut machine_env)?;
+        check_range(
+            &activity.name,
+            "lock-expiry-seconds",
+            activity.lock_expiry_seconds,
+            1..=MAX_LOCK_EXPIRY_SECS,
+        )?;
+        check_range(
+            &activity.name,
+            "max-retries",
+            activity.max_retries,
+            0..=MAX_RETRIES,
+        )?;
+        check_range(
+            &activity.name,
+            "retry-exp-backoff-ms",
+            activity.retry_exp_backoff_ms,
+            1..=MAX_RETRY_EXP_BACKOFF_MS,
+        )?;
+        check_range(
+            &activity.name,
+            "max-inflight-instances",
+            activity.max_inflight_instances,
+            1..=MAX_INFLIGHT_INSTANCES,
+        )?;
+    }
+    for workflow in config.workflow_list.iter().flatten() {
+        check_name(&workflow.name)?;
+        check_range(
+            &workflow.name,
+            "lock-expiry-seconds",
+            workflow.lock_expiry_seconds,
+            1..=MAX_LOCK_EXPIRY_SECS,
+        )?;
+        check_range(
+            &workflow.name,
+            "retry-exp-backoff-ms",
+            workflow.retry_exp_backoff_ms,
+            1..=MAX_RETRY_EXP_BACKOFF_MS,
+        )?;
+        check_range(
+            &workflow.name,
+            "max-inflight-instances",
+            workflow.max_inflight_instances,
+            1..=MAX_INFLIGHT_INSTANCES,
+        )?;
+    }
+    for webhook in config.webhook_endpoint_list.iter().flatten() {
+        check_name(&webhook.name)?;
+        check_env_vars(&webhook.name, webhook.env_vars.as_ref(), &mut machine_env)?;
+    }
+    for stub in config.activity_stub_list.iter().flatten() {
+        check_name(&stub.name)?;
+    }
+    if let Some(server_tuning) = &config.server_tuning {
+        check_server_tuning(server_tuning)?;
+    }
+    check_http_servers(config)?;
+    check_extra_files(config.extra_files.as_ref())?;
+    Ok(())
+}
+
+/// Litestream configuration replicating the Obelisk database into the MinIO bucket.
+/// The endpoint and credentials are expanded by Litestream from the final VM's environment.
+pub(crate) fn serialize_litestream_yml() -> String {
+    format!(
+        r#"dbs:
+  - path: {SQLITE_FILE_PATH}
+    replicas:
+      - type: s3
+        endpoint: ${{LITESTREAM_ENDPOINT}}
+        region: us-east-1
+        bucket: {MINIO_BUCKET_NAME}
+        path: obelisk
+        force-path-style: true
+"#
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use insta::assert_snapshot;
 
     use crate::{
         generated::obelisk_flyio::workflow::types::{
-            ActivityWasm, ObeliskConfig, Route, WebhookEndpoint, Workflow,
+            ActivityStub, ActivityWasm, ApiAuth, ApiExposure, ApiProxy, BlockingStrategy, Cron,
+            EnvLiteral, EnvVar, ExtraFile, HttpExposure, HttpServer, LogFile, LogRotation,
+            LogStyle, ObeliskConfig, OutputForwarding, Route, ServerTuning, WebhookEndpoint,
+            Workflow,
         },
-        toml::serialize_obelisk_toml,
+        toml::{serialize_litestream_yml, serialize_obelisk_toml},
     };
 
     #[test]
@@ -202,26 +843,52 @@ mod tests {
                 ActivityWasm {
                     name: "stargazers_activity_llm_chatgpt".to_string(),
                     location_oci: "docker.io/getobelisk/demo_stargazers_activity_llm_openai:2025-09-28@sha256:4b10a66c80bec625a6b0a2e8a4b5192f8a2356eca19c0a6705335771a8b8b1e8".to_string(),
-                    env_vars: Some(vec!["OPENAI_API_KEY".to_string()]),
+                    env_vars: Some(vec![EnvVar::Secret("OPENAI_API_KEY".to_string())]),
                     lock_expiry_seconds: Some(10),
+                    max_retries: None,
+                    retry_exp_backoff_ms: None,
+                    retry_on_err: None,
+                    max_inflight_instances: None,
+                    forward_stdout: None,
+                    forward_stderr: None,
                 },
                 ActivityWasm {
                     name: "stargazers_activity_github_impl".to_string(),
                     location_oci: "docker.io/getobelisk/demo_stargazers_activity_github_impl:2025-09-28@sha256:8f6fc9b1379b359e085998fa2fd7c966c450327d09770807dfba4b2f75731d72".to_string(),
-                    env_vars: Some(vec!["GITHUB_TOKEN".to_string()]),
+                    env_vars: Some(vec![EnvVar::Secret("GITHUB_TOKEN".to_string())]),
                     lock_expiry_seconds: Some(5),
+                    max_retries: None,
+                    retry_exp_backoff_ms: None,
+                    retry_on_err: None,
+                    max_inflight_instances: None,
+                    forward_stdout: None,
+                    forward_stderr: None,
                 },
                 ActivityWasm {
                     name: "stargazers_activity_db_turso".to_string(),
                     location_oci: "docker.io/getobelisk/demo_stargazers_activity_db_turso:2025-09-28@sha256:26b08b3d0c6e430944d8187a00bd9817a83ab89e11ba72d15e7533a758addf33".to_string(),
-                    env_vars: Some(vec!["TURSO_TOKEN".to_string(), "TURSO_LOCATION".to_string()]),
+                    env_vars: Some(vec![
+                        EnvVar::Secret("TURSO_TOKEN".to_string()),
+                        EnvVar::Secret("TURSO_LOCATION".to_string()),
+                    ]),
                     lock_expiry_seconds: Some(5),
+                    max_retries: None,
+                    retry_exp_backoff_ms: None,
+                    retry_on_err: None,
+                    max_inflight_instances: None,
+                    forward_stdout: None,
+                    forward_stderr: None,
                 },
             ]),
             workflow_list: Some(vec![
                 Workflow {
                     name: "stargazers_workflow".to_string(),
                     location_oci: "docker.io/getobelisk/demo_stargazers_workflow:2025-09-28@sha256:678d85e3e2f89d22794fd1ffc0217bf23510e1349ee150a54d5c82cc2ef75834".to_string(),
+                    lock_expiry_seconds: None,
+                    retry_exp_backoff_ms: None,
+                    retry_on_trap: None,
+                    blocking_strategy: None,
+                    max_inflight_instances: None,
                 },
             ]),
             webhook_endpoint_list: Some(vec![
@@ -234,12 +901,555 @@ mod tests {
                             path: "".to_string(),
                         },
                     ],
-                    env_vars: Some(vec!["GITHUB_WEBHOOK_SECRET".to_string()]),
+                    env_vars: Some(vec![EnvVar::Secret("GITHUB_WEBHOOK_SECRET".to_string())]),
+                    forward_stdout: None,
+                    forward_stderr: None,
+                    http_server: None,
                 },
             ]),
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: None,
+            cron_list: None,
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_render_stubs_and_crons() {
+        let config = ObeliskConfig {
+            activity_wasm_list: None,
+            workflow_list: Some(vec![Workflow {
+                name: "backup_workflow".to_string(),
+                location_oci: "docker.io/example/backup_workflow:2025-10-01".to_string(),
+                lock_expiry_seconds: None,
+                retry_exp_backoff_ms: None,
+                retry_on_trap: None,
+                blocking_strategy: None,
+                max_inflight_instances: None,
+            }]),
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: Some(vec![ActivityStub {
+                name: "approval_stub".to_string(),
+                location_oci: "docker.io/example/approval_stub:2025-10-01".to_string(),
+            }]),
+            cron_list: Some(vec![
+                Cron {
+                    name: "nightly_backup".to_string(),
+                    schedule: "0 3 * * *".to_string(),
+                    ffqn: "example:backup/workflow.run".to_string(),
+                    params: Some(r#"["main", 5, { retain = 7 }]"#.to_string()),
+                },
+                Cron {
+                    name: "hourly_ping".to_string(),
+                    schedule: "0 * * * *".to_string(),
+                    ffqn: "example:backup/workflow.ping".to_string(),
+                    params: None,
+                },
+            ]),
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_invalid_cron_params() {
+        let config = ObeliskConfig {
+            activity_wasm_list: None,
+            workflow_list: None,
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: None,
+            cron_list: Some(vec![Cron {
+                name: "nightly_backup".to_string(),
+                schedule: "0 3 * * *".to_string(),
+                ffqn: "example:backup/workflow.run".to_string(),
+                params: Some("\"main\"".to_string()),
+            }]),
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        };
+
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "params of cron 'nightly_backup' must be an inline array",
+            err.to_string()
+        );
+    }
+
+    fn activity(name: &str) -> ActivityWasm {
+        ActivityWasm {
+            name: name.to_string(),
+            location_oci: format!("docker.io/example/{name}:2025-10-01"),
+            env_vars: None,
+            lock_expiry_seconds: None,
+            max_retries: None,
+            retry_exp_backoff_ms: None,
+            retry_on_err: None,
+            max_inflight_instances: None,
+            forward_stdout: None,
+            forward_stderr: None,
+        }
+    }
+
+    fn literal(key: &str, value: &str, in_machine_env: bool) -> EnvVar {
+        EnvVar::Literal(EnvLiteral {
+            key: key.to_string(),
+            value: value.to_string(),
+            in_machine_env,
+        })
+    }
+
+    fn config_with_activities(activities: Vec<ActivityWasm>) -> ObeliskConfig {
+        ObeliskConfig {
+            activity_wasm_list: Some(activities),
+            workflow_list: None,
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: None,
+            cron_list: None,
+            server_tuning: None,
+            toml_overlay: None,
+            extra_files: None,
+        }
+    }
+
+    fn server_tuning() -> ServerTuning {
+        ServerTuning {
+            log_level: None,
+            log_style: None,
+            log_file: None,
+            otlp_endpoint: None,
+            backtrace_persist: None,
+            sqlite_pragmas: None,
+        }
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_render_component_settings() {
+        let config = ObeliskConfig {
+            activity_wasm_list: Some(vec![ActivityWasm {
+                env_vars: Some(vec![
+                    EnvVar::Secret("API_TOKEN".to_string()),
+                    literal("LOG_LEVEL", "debug", false),
+                    literal("API_URL", "https://example.com", true),
+                    EnvVar::MachineEnv("FLY_REGION".to_string()),
+                ]),
+                lock_expiry_seconds: Some(30),
+                max_retries: Some(3),
+                retry_exp_backoff_ms: Some(500),
+                retry_on_err: Some(false),
+                max_inflight_instances: Some(10),
+                forward_stdout: Some(OutputForwarding::Stderr),
+                forward_stderr: Some(OutputForwarding::None),
+                ..activity("http_activity")
+            }]),
+            workflow_list: Some(vec![Workflow {
+                name: "backup_workflow".to_string(),
+                location_oci: "docker.io/example/backup_workflow:2025-10-01".to_string(),
+                lock_expiry_seconds: None,
+                retry_exp_backoff_ms: Some(1000),
+                retry_on_trap: Some(true),
+                blocking_strategy: Some(BlockingStrategy::Await),
+                max_inflight_instances: Some(100),
+            }]),
+            webhook_endpoint_list: None,
+            http_server_list: None,
+            api_exposure: None,
+            activity_stub_list: None,
+            cron_list: None,
+            server_tuning: Some(ServerTuning {
+                backtrace_persist: Some(true),
+                ..server_tuning()
+            }),
+            toml_overlay: None,
+            extra_files: None,
         };
 
         let toml = serialize_obelisk_toml(&config).unwrap();
         assert_snapshot!(toml);
     }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_out_of_range_values() {
+        let config = config_with_activities(vec![ActivityWasm {
+            lock_expiry_seconds: Some(0),
+            ..activity("http_activity")
+        }]);
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "lock-expiry-seconds of 'http_activity' must be between 1 and 86400, got 0",
+            err.to_string()
+        );
+
+        let config = config_with_activities(vec![ActivityWasm {
+            max_inflight_instances: Some(1_000_000),
+            ..activity("http_activity")
+        }]);
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "max-inflight-instances of 'http_activity' must be between 1 and 10000, got 1000000",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_duplicate_names() {
+        let config =
+            config_with_activities(vec![activity("http_activity"), activity("http_activity")]);
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "component name 'http_activity' is not unique",
+            err.to_string()
+        );
+
+        let config = config_with_activities(vec![activity("webhook_healthcheck")]);
+        assert!(serialize_obelisk_toml(&config).is_err());
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_invalid_env_vars() {
+        let config = config_with_activities(vec![ActivityWasm {
+            env_vars: Some(vec![literal("A=B", "c", false)]),
+            ..activity("http_activity")
+        }]);
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "invalid environment variable name 'A=B' of 'http_activity'",
+            err.to_string()
+        );
+
+        let config = config_with_activities(vec![
+            ActivityWasm {
+                env_vars: Some(vec![literal("API_URL", "https://a.example.com", true)]),
+                ..activity("activity_a")
+            },
+            ActivityWasm {
+                env_vars: Some(vec![literal("API_URL", "https://b.example.com", true)]),
+                ..activity("activity_b")
+            },
+        ]);
+        let err = serialize_obelisk_toml(&config).unwrap_err();
+        assert_eq!(
+            "environment variable 'API_URL' of 'activity_b' has conflicting values",
+            err.to_string()
+        );
+
+        let config = config_with_activities(vec![ActivityWasm {
+            env_vars: Some(vec![literal("LITESTREAM_ENDPOINT", "http://x", true)]),
+            ..activity("http_activity")
+        }]);
+        assert!(serialize_obelisk_toml(&config).is_err());
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_apply_server_tuning() {
+        let config = ObeliskConfig {
+            server_tuning: Some(ServerTuning {
+                log_level: Some("info,obelisk=debug".to_string()),
+                log_style: Some(LogStyle::Json),
+                log_file: Some(LogFile {
+                    level: None,
+                    style: Some(LogStyle::PlainCompact),
+                    rotation: Some(LogRotation::Hourly),
+                }),
+                otlp_endpoint: Some("http://otel-collector.flycast:4317".to_string()),
+                backtrace_persist: None,
+                sqlite_pragmas: Some(vec![
+                    ("cache_size".to_string(), "10000".to_string()),
+                    ("busy_timeout".to_string(), "5000".to_string()),
+                ]),
+            }),
+            ..config_with_activities(Vec::new())
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_invalid_server_tuning() {
+        let tuning_error = |server_tuning: ServerTuning| {
+            let config = ObeliskConfig {
+                server_tuning: Some(server_tuning),
+                ..config_with_activities(Vec::new())
+            };
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        };
+        assert_eq!(
+            "log level must not be empty",
+            tuning_error(ServerTuning {
+                log_file: Some(LogFile {
+                    level: Some(" ".to_string()),
+                    style: None,
+                    rotation: None,
+                }),
+                ..server_tuning()
+            })
+        );
+        assert_eq!(
+            "OTLP endpoint 'otel-collector:4317' must be an http:// or https:// URL",
+            tuning_error(ServerTuning {
+                otlp_endpoint: Some("otel-collector:4317".to_string()),
+                ..server_tuning()
+            })
+        );
+        assert_eq!(
+            "invalid SQLite pragma name 'cache_size = 1; --'",
+            tuning_error(ServerTuning {
+                sqlite_pragmas: Some(vec![("cache_size = 1; --".to_string(), String::new())]),
+                ..server_tuning()
+            })
+        );
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_merge_overlay() {
+        let config = ObeliskConfig {
+            toml_overlay: Some(
+                r#"
+sqlite.pragma = { "journal_size_limit" = "1000000" }
+log.stdout.level = "info"
+[otlp]
+enabled = true
+"#
+                .to_string(),
+            ),
+            ..config_with_activities(vec![activity("http_activity")])
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_reserved_overlay_keys() {
+        let overlay_error = |overlay: &str| {
+            let config = ObeliskConfig {
+                toml_overlay: Some(overlay.to_string()),
+                ..config_with_activities(Vec::new())
+            };
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        };
+        assert_eq!(
+            "toml-overlay must not set 'http_server', it is set by the deployer",
+            overlay_error("[[http_server]]\nname = \"public\"")
+        );
+        assert_eq!(
+            "toml-overlay must not set 'sqlite.directory', it is set by the deployer",
+            overlay_error("sqlite.directory = \"/tmp\"")
+        );
+        // Replacing the whole table would drop the cache directories.
+        assert_eq!(
+            "toml-overlay must not set 'wasm', it is set by the deployer",
+            overlay_error("wasm = false")
+        );
+        assert_eq!("toml-overlay is not valid TOML", overlay_error("[sqlite"));
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_invalid_extra_files() {
+        let extra_file_error = |path: &str| {
+            let config = ObeliskConfig {
+                extra_files: Some(vec![ExtraFile {
+                    path: path.to_string(),
+                    contents: String::new(),
+                }]),
+                ..config_with_activities(Vec::new())
+            };
+            serialize_obelisk_toml(&config).map_err(|err| err.to_string())
+        };
+        assert!(extra_file_error("/volume/prompts/summary.txt").is_ok());
+        assert_eq!(
+            Err("extra file '/etc/ssl/ca.pem' must be placed under /volume".to_string()),
+            extra_file_error("/etc/ssl/ca.pem")
+        );
+        assert_eq!(
+            Err("path of extra file '/volume/../etc/passwd' must be normalized".to_string()),
+            extra_file_error("/volume/../etc/passwd")
+        );
+        assert_eq!(
+            Err(
+                "extra file '/volume/obelisk.toml' would overwrite a file managed by the deployer"
+                    .to_string()
+            ),
+            extra_file_error("/volume/obelisk.toml")
+        );
+        assert!(extra_file_error("/volume/obelisk-sqlite/obelisk.sqlite").is_err());
+
+        let config = ObeliskConfig {
+            extra_files: Some(vec![
+                ExtraFile {
+                    path: "/volume/ca.pem".to_string(),
+                    contents: String::new(),
+                };
+                2
+            ]),
+            ..config_with_activities(Vec::new())
+        };
+        assert_eq!(
+            "extra file '/volume/ca.pem' is not unique",
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        );
+    }
+
+    fn http_server(name: &str, internal_port: u16, exposure: HttpExposure) -> HttpServer {
+        HttpServer {
+            name: name.to_string(),
+            internal_port,
+            external_port: None,
+            exposure,
+        }
+    }
+
+    fn webhook(name: &str, http_server: Option<&str>) -> WebhookEndpoint {
+        WebhookEndpoint {
+            name: name.to_string(),
+            location_oci: "docker.io/example/webhook:2025-10-01".to_string(),
+            routes: vec![Route {
+                methods: vec!["POST".to_string()],
+                path: "/hook".to_string(),
+            }],
+            env_vars: None,
+            forward_stdout: None,
+            forward_stderr: None,
+            http_server: http_server.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_render_http_servers() {
+        let config = ObeliskConfig {
+            webhook_endpoint_list: Some(vec![
+                webhook("public_webhook", None),
+                webhook("internal_webhook", Some("internal")),
+            ]),
+            http_server_list: Some(vec![
+                http_server("webhook_server", 9090, HttpExposure::PublicTls),
+                http_server("internal", 9000, HttpExposure::PrivateOnly),
+            ]),
+            ..config_with_activities(Vec::new())
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_reject_invalid_http_servers() {
+        let http_server_error = |http_servers: Vec<HttpServer>| {
+            let config = ObeliskConfig {
+                http_server_list: Some(http_servers),
+                ..config_with_activities(Vec::new())
+            };
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        };
+        assert_eq!(
+            "HTTP server name 'public' is not unique",
+            http_server_error(vec![
+                http_server("public", 9000, HttpExposure::PublicTls),
+                http_server("public", 9001, HttpExposure::PublicHttp),
+            ])
+        );
+        assert_eq!(
+            "internal port 5005 of HTTP server 'public' is not available",
+            http_server_error(vec![http_server("public", 5005, HttpExposure::PublicTls)])
+        );
+        // Both default to the external port 443.
+        assert_eq!(
+            "external port 443 of HTTP server 'b' is not available",
+            http_server_error(vec![
+                http_server("a", 9000, HttpExposure::PublicTls),
+                http_server("b", 9001, HttpExposure::PublicTls),
+            ])
+        );
+        assert_eq!(
+            "HTTP server 'internal' is private-only and must not have an external port",
+            http_server_error(vec![HttpServer {
+                external_port: Some(8443),
+                ..http_server("internal", 9000, HttpExposure::PrivateOnly)
+            }])
+        );
+
+        let config = ObeliskConfig {
+            webhook_endpoint_list: Some(vec![webhook("webhook", None)]),
+            http_server_list: Some(vec![http_server(
+                "internal",
+                9000,
+                HttpExposure::PrivateOnly,
+            )]),
+            ..config_with_activities(Vec::new())
+        };
+        assert_eq!(
+            "HTTP server 'webhook_server' of 'webhook' is not defined",
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_render_api_proxy() {
+        let config = ObeliskConfig {
+            api_exposure: Some(ApiExposure::Public(ApiProxy {
+                location_oci: "docker.io/example/webhook_api_proxy:2025-10-01".to_string(),
+                auth: ApiAuth::Token,
+                external_port: Some(9443),
+            })),
+            ..config_with_activities(Vec::new())
+        };
+
+        let toml = serialize_obelisk_toml(&config).unwrap();
+        assert_snapshot!(toml);
+    }
+
+    #[test]
+    fn serialize_obelisk_toml_should_apply_api_exposure() {
+        let config = ObeliskConfig {
+            api_exposure: Some(ApiExposure::PrivateOnly),
+            ..config_with_activities(Vec::new())
+        };
+        let private_toml = serialize_obelisk_toml(&config).unwrap();
+        assert!(private_toml.contains(r#"listening_addr = "[::]:5005""#));
+        assert!(private_toml.contains(r#"listening_addr = "[::]:8080""#));
+        // Without `api-exposure` the API and the WebUI stay on the private network as well.
+        assert_eq!(
+            private_toml,
+            serialize_obelisk_toml(&config_with_activities(Vec::new())).unwrap()
+        );
+
+        let config = ObeliskConfig {
+            http_server_list: Some(vec![HttpServer {
+                external_port: Some(8443),
+                ..http_server("public", 9000, HttpExposure::PublicTls)
+            }]),
+            api_exposure: Some(ApiExposure::Public(ApiProxy {
+                location_oci: "docker.io/example/webhook_api_proxy:2025-10-01".to_string(),
+                auth: ApiAuth::Basic,
+                external_port: None,
+            })),
+            ..config_with_activities(Vec::new())
+        };
+        assert_eq!(
+            "external port 8443 of HTTP server 'api_proxy_server' is not available",
+            serialize_obelisk_toml(&config).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn serialize_litestream_yml_should_produce_correct_config() {
+        assert_snapshot!(serialize_litestream_yml());
+    }
 }
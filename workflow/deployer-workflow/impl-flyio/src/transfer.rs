@@ -0,0 +1,225 @@
+use crate::activities::Activities;
+use crate::exec_checked;
+use crate::generated::obelisk_flyio::workflow::types::{ErrorKind, StepError};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use sha2::{Digest as _, Sha256};
+
+/// Bytes written by a single `machines::exec`. A multiple of 3, so that chunks encode without padding;
+/// once encoded they stay well below the 128 KiB limit of a single command line argument.
+const CHUNK_SIZE: usize = 48 * 1024;
+// The chunk and the path are passed as positional parameters, the shell never interprets them.
+const WRITE_CHUNK_SCRIPT: &str = r#"printf %s "$1" | base64 -d > "$2""#;
+const APPEND_CHUNK_SCRIPT: &str = r#"printf %s "$1" | base64 -d >> "$2""#;
+
+/// Lowercase hex SHA-256 digest, as printed by `sha256sum`.
+pub(crate) fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// A chunk written by [`put_file`], parsed back from its command.
+pub(crate) struct ChunkWrite<'a> {
+    pub(crate) path: &'a str,
+    append: bool,
+    encoded: &'a str,
+}
+
+impl<'a> ChunkWrite<'a> {
+    pub(crate) fn parse(command: &'a [String]) -> Option<Self> {
+        match command {
+            [sh, c, script, _, encoded, path]
+                if sh == "sh"
+                    && c == "-c"
+                    && (script == WRITE_CHUNK_SCRIPT || script == APPEND_CHUNK_SCRIPT) =>
+            {
+                Some(ChunkWrite {
+                    path,
+                    append: script == APPEND_CHUNK_SCRIPT,
+                    encoded,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply the chunk to the file contents as the shell would.
+    pub(crate) fn apply(&self, file: &mut Vec<u8>) -> Result<(), String> {
+        let chunk = STANDARD
+            .decode(self.encoded)
+            .map_err(|err| err.to_string())?;
+        if !self.append {
+            file.clear();
+        }
+        file.extend(chunk);
+        Ok(())
+    }
+}
+
+/// Place the file on the VM's filesystem.
+///
+/// The contents are transferred base64-encoded in chunks into a temporary file next to the target,
+/// named after the expected checksum so that it cannot clash with another file. Its SHA-256
+/// checksum is compared with the expected one before it is renamed to the target path.
+/// On failure the target is left untouched, a temporary file failing the check is removed.
+pub(crate) fn put_file(
+    act: &impl Activities,
+    app_name: &str,
+    machine_id: &str,
+    path: &str,
+    contents: &[u8],
+) -> Result<(), StepError> {
+    let expected = sha256_hex(contents);
+    let temp_path = format!("{path}.{}.tmp", &expected[..16]);
+    // An empty file is created by writing a single empty chunk.
+    let chunks: Vec<_> = if contents.is_empty() {
+        vec![String::new()]
+    } else {
+        contents
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| STANDARD.encode(chunk))
+            .collect()
+    };
+    for (idx, chunk) in chunks.into_iter().enumerate() {
+        let script = if idx == 0 {
+            WRITE_CHUNK_SCRIPT
+        } else {
+            APPEND_CHUNK_SCRIPT
+        };
+        exec_checked(
+            act,
+            app_name,
+            machine_id,
+            "write-file",
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                script.to_string(),
+                "sh".to_string(),
+                chunk,
+                temp_path.clone(),
+            ],
+        )?;
+    }
+    let exec_response = exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "verify-file",
+        vec!["sha256sum".to_string(), temp_path.clone()],
+    )?;
+    let actual = exec_response
+        .stdout
+        .as_deref()
+        .and_then(|stdout| stdout.split_whitespace().next())
+        .unwrap_or_default();
+    if actual != expected {
+        let message = format!("SHA-256 of {temp_path} is '{actual}', expected '{expected}'");
+        // The mismatch is reported even if the cleanup fails.
+        let _ = exec_checked(
+            act,
+            app_name,
+            machine_id,
+            "remove-file",
+            vec!["rm".to_string(), "-f".to_string(), temp_path.clone()],
+        );
+        return Err(StepError {
+            kind: ErrorKind::ChecksumMismatch,
+            ..StepError::exec("verify-file", machine_id, message, &exec_response)
+        });
+    }
+    exec_checked(
+        act,
+        app_name,
+        machine_id,
+        "rename-file",
+        vec!["mv".to_string(), temp_path, path.to_string()],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CHUNK_SIZE, put_file};
+    use crate::fake::{APP, FakeFly, options};
+    use crate::generated::obelisk_flyio::workflow::types::ErrorKind;
+    use crate::{create_volume, start_temp_vm};
+
+    /// A temporary VM with a volume attached, returning the volume and the VM IDs.
+    fn temp_vm(fly: &FakeFly) -> (String, String) {
+        let options = options();
+        let volume_id = create_volume(fly, APP, "db", &options).unwrap();
+        let machine_id = start_temp_vm(fly, APP, &volume_id, &options, None).unwrap();
+        (volume_id, machine_id)
+    }
+
+    #[test]
+    fn put_file_should_transfer_contents_verbatim() {
+        let fly = FakeFly::with_app(APP);
+        let (volume_id, machine_id) = temp_vm(&fly);
+        let line = "value = \"$HOME `id` ${X} ěšč\"\nEOF\n";
+        let contents = line.repeat(CHUNK_SIZE * 2 / line.len() + 1);
+        put_file(
+            &fly,
+            APP,
+            &machine_id,
+            "/volume/a.toml",
+            contents.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(Some(contents), fly.file(&volume_id, "/volume/a.toml"));
+        assert_eq!(
+            3,
+            fly.calls("machines::exec")
+                .iter()
+                .filter(|call| call.contains("base64 -d"))
+                .count()
+        );
+
+        put_file(&fly, APP, &machine_id, "/volume/empty", b"").unwrap();
+        assert_eq!(Some(String::new()), fly.file(&volume_id, "/volume/empty"));
+        assert_eq!(
+            vec!["/volume/a.toml", "/volume/empty"],
+            fly.state().files[&volume_id].keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn put_file_should_not_clobber_file_named_like_temporary() {
+        let fly = FakeFly::with_app(APP);
+        let (volume_id, machine_id) = temp_vm(&fly);
+        put_file(&fly, APP, &machine_id, "/volume/a.toml.tmp", b"extra").unwrap();
+        put_file(&fly, APP, &machine_id, "/volume/a.toml", b"config").unwrap();
+        assert_eq!(
+            Some("extra".to_string()),
+            fly.file(&volume_id, "/volume/a.toml.tmp")
+        );
+        assert_eq!(
+            Some("config".to_string()),
+            fly.file(&volume_id, "/volume/a.toml")
+        );
+    }
+
+    #[test]
+    fn put_file_should_keep_target_on_checksum_mismatch() {
+        let fly = FakeFly::with_app(APP);
+        let (volume_id, machine_id) = temp_vm(&fly);
+        put_file(&fly, APP, &machine_id, "/volume/a.toml", b"old").unwrap();
+        fly.fail_exec("sha256sum", 0, "");
+        let err = put_file(&fly, APP, &machine_id, "/volume/a.toml", b"new").unwrap_err();
+        assert_eq!(ErrorKind::ChecksumMismatch, err.kind);
+        assert_eq!("verify-file", err.step);
+        assert_eq!(
+            Some("old".to_string()),
+            fly.file(&volume_id, "/volume/a.toml")
+        );
+        assert_eq!(
+            vec!["/volume/a.toml"],
+            fly.state().files[&volume_id]
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::activities::Activities;
+use crate::generated::obelisk::types::time::{Duration as SchedulingDuration, ScheduleAt};
+use crate::generated::obelisk_flyio::{
+    activity_fly_http::machines::{HostStatus, MachineState},
+    workflow::types::{Remediation, RemediationAction, WatchError, WatchOptions, WatchReport},
+};
+use crate::{VM_NAME_FINAL, is_endpoint_healthy, replace_final_vm, restart_final_vm};
+
+/// The final VM is started on a reachable host and its health check passes.
+/// `None` if the machines cannot be listed, a Fly.io API failure says nothing about the VM.
+fn is_healthy(act: &impl Activities, app_name: &str) -> Option<bool> {
+    let machines = act.machines_list(app_name).ok()?;
+    let vm_healthy = machines.iter().any(|machine| {
+        machine.name == VM_NAME_FINAL
+            && machine.state == MachineState::Started
+            && machine.host_status == HostStatus::Ok
+    });
+    Some(vm_healthy && is_endpoint_healthy(act, app_name))
+}
+
+/// Probe the final VM periodically, restarting and then replacing it when it stays unhealthy.
+pub(crate) fn watch(
+    act: &impl Activities,
+    app_name: &str,
+    options: &WatchOptions,
+) -> Result<WatchReport, WatchError> {
+    let mut report = WatchReport {
+        probes: 0,
+        inconclusive_probes: 0,
+        remediations: Vec::new(),
+    };
+    let (mut failures, mut successes) = (0, 0);
+    let (mut restarts, mut replacements) = (0, 0);
+    while report.probes < options.max_probes() {
+        if report.probes > 0 {
+            act.sleep(ScheduleAt::In(SchedulingDuration::Seconds(u64::from(
+                options.interval_secs(),
+            ))));
+        }
+        report.probes += 1;
+        match is_healthy(act, app_name) {
+            None => {
+                if matches!(act.apps_get(app_name), Ok(None)) {
+                    return Err(WatchError::AppDeleted);
+                }
+                report.inconclusive_probes += 1;
+                continue;
+            }
+            Some(true) => {
+                failures = 0;
+                successes += 1;
+                // Forgive the remediations only once the VM has stayed healthy for a while.
+                if successes >= options.recovery_probes() {
+                    restarts = 0;
+                    replacements = 0;
+                }
+                continue;
+            }
+            Some(false) => {}
+        }
+        successes = 0;
+        if matches!(act.apps_get(app_name), Ok(None)) {
+            return Err(WatchError::AppDeleted);
+        }
+        failures += 1;
+        if failures < options.failure_threshold() {
+            continue;
+        }
+        // Give the remediated VM the same number of probes to recover.
+        failures = 0;
+        let (action, res) = if restarts < options.max_restarts() {
+            restarts += 1;
+            (RemediationAction::Restart, restart_final_vm(act, app_name))
+        } else if replacements < options.max_replacements() {
+            replacements += 1;
+            (RemediationAction::Replace, replace_final_vm(act, app_name))
+        } else {
+            return Err(WatchError::Failed(report));
+        };
+        report.remediations.push(Remediation {
+            action,
+            probe: report.probes,
+            error: res.err(),
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::watch;
+    use crate::fake::{APP, FakeFly, deployed};
+    use crate::generated::obelisk_flyio::{
+        activity_fly_http::machines::HostStatus,
+        workflow::types::{AppInitModifyError, RemediationAction, WatchError, WatchOptions},
+    };
+    use crate::{VM_NAME_FINAL, VOLUME_NAME};
+
+    fn watch_options(max_probes: u32) -> WatchOptions {
+        WatchOptions {
+            interval_secs: Some(60),
+            failure_threshold: Some(2),
+            max_restarts: Some(1),
+            max_replacements: Some(1),
+            recovery_probes: Some(3),
+            max_probes: Some(max_probes),
+        }
+    }
+
+    fn final_vm_id(fly: &FakeFly) -> String {
+        fly.app(APP)
+            .machines
+            .iter()
+            .find(|machine| machine.name == VM_NAME_FINAL)
+            .map(|machine| machine.id.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn healthy_app_should_not_be_remediated() {
+        let fly = deployed("");
+        fly.set_healthy_since(0);
+        let start = fly.state().now;
+        let report = watch(&fly, APP, &watch_options(5)).unwrap();
+        assert_eq!(5, report.probes);
+        assert!(report.remediations.is_empty());
+        assert_eq!(start + 240, fly.state().now);
+    }
+
+    #[test]
+    fn unhealthy_vm_should_be_restarted_until_it_recovers() {
+        let fly = deployed("");
+        // The first two probes fail, the VM is restarted, the third probe passes.
+        let start = fly.state().now;
+        fly.set_healthy_since(start + 100);
+        let report = watch(&fly, APP, &watch_options(4)).unwrap();
+        assert_eq!(1, report.remediations.len());
+        assert_eq!(RemediationAction::Restart, report.remediations[0].action);
+        assert_eq!(2, report.remediations[0].probe);
+        assert!(report.remediations[0].error.is_none());
+        assert_eq!(1, fly.calls("machines::restart").len());
+    }
+
+    #[test]
+    fn unreachable_host_should_trigger_restart() {
+        let fly = deployed("");
+        fly.set_healthy_since(0);
+        for machine in &mut fly.state_mut().apps.get_mut(APP).unwrap().machines {
+            machine.host_status = HostStatus::Unreachable;
+        }
+        let report = watch(&fly, APP, &watch_options(2)).unwrap();
+        assert_eq!(1, report.remediations.len());
+        assert_eq!(RemediationAction::Restart, report.remediations[0].action);
+    }
+
+    #[test]
+    fn watch_should_fail_when_restarts_and_replacements_do_not_help() {
+        let fly = deployed("");
+        let original_vm_id = final_vm_id(&fly);
+        let err = watch(&fly, APP, &watch_options(10)).unwrap_err();
+        let WatchError::Failed(report) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(
+            vec![RemediationAction::Restart, RemediationAction::Replace],
+            report
+                .remediations
+                .iter()
+                .map(|remediation| remediation.action)
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            report
+                .remediations
+                .iter()
+                .all(|remediation| remediation.error.is_none())
+        );
+        assert_eq!(6, report.probes);
+        // A fresh machine mounting the same volume.
+        let new_vm_id = final_vm_id(&fly);
+        assert_ne!(original_vm_id, new_vm_id);
+        assert!(
+            fly.calls("machines::delete")
+                .contains(&format!("machines::delete {original_vm_id}"))
+        );
+        let app = fly.app(APP);
+        assert_eq!(
+            Some(new_vm_id),
+            app.volumes
+                .iter()
+                .find(|volume| volume.name == VOLUME_NAME)
+                .and_then(|volume| volume.attached_machine_id.clone())
+        );
+    }
+
+    #[test]
+    fn failed_machine_listing_should_not_count_as_failure() {
+        let fly = deployed("");
+        fly.set_healthy_since(0);
+        for _ in 0..3 {
+            fly.fail("machines::list", "503 Service Unavailable");
+        }
+        let report = watch(&fly, APP, &watch_options(5)).unwrap();
+        assert_eq!(5, report.probes);
+        assert_eq!(3, report.inconclusive_probes);
+        assert!(report.remediations.is_empty());
+        assert!(fly.calls("machines::restart").is_empty());
+    }
+
+    #[test]
+    fn watch_should_be_bounded_by_default() {
+        let fly = deployed("");
+        fly.set_healthy_since(0);
+        let options = WatchOptions {
+            max_probes: None,
+            ..watch_options(0)
+        };
+        let report = watch(&fly, APP, &options).unwrap();
+        assert_eq!(1440, report.probes);
+    }
+
+    #[test]
+    fn single_healthy_probes_should_not_reset_the_escalation() {
+        let fly = deployed("");
+        // Each remediation helps for a single probe only.
+        fly.script_health(&[false, false, true, false, false, true, false, false]);
+        let err = watch(&fly, APP, &watch_options(20)).unwrap_err();
+        let WatchError::Failed(report) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(2, report.remediations.len());
+        assert_eq!(8, report.probes);
+    }
+
+    #[test]
+    fn sustained_recovery_should_reset_the_escalation() {
+        let fly = deployed("");
+        fly.script_health(&[
+            false, false, true, true, true, false, false, true, true, true,
+        ]);
+        let report = watch(&fly, APP, &watch_options(10)).unwrap();
+        assert_eq!(
+            vec![RemediationAction::Restart, RemediationAction::Restart],
+            report
+                .remediations
+                .iter()
+                .map(|remediation| remediation.action)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn failed_remediation_should_report_typed_error() {
+        let fly = deployed("");
+        fly.fail("machines::restart", "503 Service Unavailable");
+        let report = watch(&fly, APP, &watch_options(2)).unwrap();
+        let Some(AppInitModifyError::FinalVmError(step_error)) = &report.remediations[0].error
+        else {
+            panic!("unexpected remediation {:?}", report.remediations[0]);
+        };
+        assert_eq!(Some(503), step_error.status);
+    }
+
+    #[test]
+    fn deleted_app_should_stop_the_watch() {
+        let fly = FakeFly::default();
+        let err = watch(&fly, APP, &watch_options(10)).unwrap_err();
+        assert!(matches!(err, WatchError::AppDeleted));
+    }
+}